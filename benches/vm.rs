@@ -0,0 +1,63 @@
+//! Compares evaluating one formula for many values of `x` by re-lexing it each time, by running
+//! its resolved tokens, and by running its bytecode.
+//!
+//! Run with `cargo bench --bench vm`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use calc_lang::{Engine, Value, bytecode::Vm};
+
+const FORMULA: &str = "y = x * x * 3 + sqrt(x) / (x + 1) - max(x, 2) ^ 2; y / 2";
+
+const ITERATIONS: u32 = 200_000;
+
+fn bench(name: &str, mut evaluate: impl FnMut(f64) -> Value) -> Duration {
+    let start = Instant::now();
+
+    for i in 0..ITERATIONS {
+        black_box(evaluate(black_box(i as f64)));
+    }
+
+    let elapsed = start.elapsed() / ITERATIONS;
+
+    println!("{name: <12}{elapsed:?} per evaluation");
+
+    elapsed
+}
+
+fn main() {
+    let mut engine = Engine::new();
+
+    let eval = bench("eval", |x| {
+        engine.set_var("x", x);
+        engine.eval(FORMULA).unwrap()
+    });
+
+    let mut engine = Engine::new();
+    let program = engine.compile(FORMULA).unwrap();
+
+    let run = bench("run", |x| {
+        engine.set_var("x", x);
+        engine.run(&program).unwrap()
+    });
+
+    let mut engine = Engine::new();
+    let compiled = engine.compile_bytecode(FORMULA).unwrap();
+
+    let mut vm = Vm::new(compiled.chunk());
+    let slot = engine.slot(&compiled, "x").unwrap();
+
+    let bytecode = bench("bytecode", |x| {
+        vm.set(slot, x);
+        engine.execute(&compiled, &mut vm).unwrap()
+    });
+
+    println!(
+        "bytecode is {:.1}x faster than eval and {:.1}x faster than run",
+        eval.as_secs_f64() / bytecode.as_secs_f64(),
+        run.as_secs_f64() / bytecode.as_secs_f64(),
+    );
+}
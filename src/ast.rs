@@ -0,0 +1,670 @@
+use std::{rc::Rc, slice};
+
+use parsr::{
+    interner::Id,
+    token::span::{Span, Spanned},
+};
+
+use crate::{
+    functions::{Function, UserFunction},
+    run::{
+        Atomicity, RunErrorContainer, Stack, State, assign, binary, call, end_statement, history,
+        number, print, transaction,
+    },
+    tokens::{Operator, ProcessorError, Token, TokenError, Value},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Neg,
+    Pos,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+}
+
+impl BinaryOp {
+    pub fn operator(self) -> Operator {
+        match self {
+            BinaryOp::Add => Operator::Add,
+            BinaryOp::Sub => Operator::Sub,
+            BinaryOp::Mul => Operator::Mul,
+            BinaryOp::Div => Operator::Div,
+            BinaryOp::Pow => Operator::Pow,
+        }
+    }
+}
+
+/// An expression tree, every node spanning the source it was resolved from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Ident(Id),
+    /// An earlier result, `_1` being the first
+    History(usize),
+    Unary {
+        op: UnaryOp,
+        operand: Box<Spanned<Expr>>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Spanned<Expr>>,
+        right: Box<Spanned<Expr>>,
+    },
+    /// Checked when evaluated, so `1 = 2` is a tree but a runtime error
+    Assign {
+        target: Box<Spanned<Expr>>,
+        value: Box<Spanned<Expr>>,
+    },
+    Print(Box<Spanned<Expr>>),
+    Call {
+        function: Id,
+        arguments: Vec<Spanned<Expr>>,
+    },
+    Define {
+        name: Id,
+        params: Vec<Spanned<Id>>,
+        body: Box<Spanned<Expr>>,
+    },
+    /// Statements separated by `;`
+    Seq(Vec<Spanned<Expr>>),
+}
+
+type TokenResult = Result<Spanned<Token>, Spanned<TokenError>>;
+
+/// Builds a tree from the tokens of `resolved_tokens`, as a `Seq` of its statements.
+pub fn build(
+    tokens: impl Iterator<Item = TokenResult>,
+) -> Result<Spanned<Expr>, Spanned<TokenError>> {
+    let mut tokens = tokens;
+
+    let statements = build_statements(&mut tokens)?;
+
+    let span = match (statements.first(), statements.last()) {
+        (Some(first), Some(last)) => first.span.from_self_to_other(last.span),
+        _ => Default::default(),
+    };
+
+    Ok(span.over(Expr::Seq(statements)))
+}
+
+/// Pops an operand for the operator at `span`, which hand-built tokens may be missing.
+fn pop(stack: &mut Vec<Spanned<Expr>>, span: Span) -> Result<Spanned<Expr>, Spanned<TokenError>> {
+    stack.pop().ok_or_else(|| {
+        span.over(TokenError::ProcessorError(
+            ProcessorError::ExpectedExpression,
+        ))
+    })
+}
+
+/// Pops the last `count` operands, in order.
+fn pop_many(
+    stack: &mut Vec<Spanned<Expr>>,
+    span: Span,
+    count: usize,
+) -> Result<Vec<Spanned<Expr>>, Spanned<TokenError>> {
+    match stack.len().checked_sub(count) {
+        Some(start) => Ok(stack.split_off(start)),
+        None => Err(span.over(TokenError::ProcessorError(
+            ProcessorError::ExpectedExpression,
+        ))),
+    }
+}
+
+fn build_statements(
+    tokens: &mut dyn Iterator<Item = TokenResult>,
+) -> Result<Vec<Spanned<Expr>>, Spanned<TokenError>> {
+    let mut stack = Vec::<Spanned<Expr>>::new();
+    let mut statements = Vec::new();
+
+    while let Some(token) = tokens.next() {
+        let token = token?;
+
+        let node = match token.inner {
+            Token::Value(Value::Number(num)) => token.span.over(Expr::Number(num)),
+            Token::Value(Value::Ident(id)) => token.span.over(Expr::Ident(id)),
+            Token::Value(Value::History(index)) => token.span.over(Expr::History(index)),
+            Token::Value(Value::Call(id)) => token.span.over(Expr::Call {
+                function: id,
+                arguments: Vec::new(),
+            }),
+            Token::Operator(operator) => match operator {
+                Operator::Equals => {
+                    let value = pop(&mut stack, token.span)?;
+                    let target = pop(&mut stack, token.span)?;
+
+                    target
+                        .span
+                        .from_self_to_other(value.span)
+                        .over(Expr::Assign {
+                            target: Box::new(target),
+                            value: Box::new(value),
+                        })
+                }
+                Operator::Add | Operator::Sub | Operator::Mul | Operator::Div | Operator::Pow => {
+                    let right = pop(&mut stack, token.span)?;
+                    let left = pop(&mut stack, token.span)?;
+
+                    let op = match operator {
+                        Operator::Add => BinaryOp::Add,
+                        Operator::Sub => BinaryOp::Sub,
+                        Operator::Mul => BinaryOp::Mul,
+                        Operator::Div => BinaryOp::Div,
+                        _ => BinaryOp::Pow,
+                    };
+
+                    left.span.from_self_to_other(right.span).over(Expr::Binary {
+                        op,
+                        left: Box::new(left),
+                        right: Box::new(right),
+                    })
+                }
+                Operator::Neg | Operator::Pos => {
+                    let operand = pop(&mut stack, token.span)?;
+
+                    let op = match operator {
+                        Operator::Neg => UnaryOp::Neg,
+                        _ => UnaryOp::Pos,
+                    };
+
+                    token
+                        .span
+                        .from_self_to_other(operand.span)
+                        .over(Expr::Unary {
+                            op,
+                            operand: Box::new(operand),
+                        })
+                }
+                Operator::Print => {
+                    let operand = pop(&mut stack, token.span)?;
+
+                    token
+                        .span
+                        .from_self_to_other(operand.span)
+                        .over(Expr::Print(Box::new(operand)))
+                }
+                Operator::Comma => continue,
+                Operator::Call(id, count) => {
+                    let arguments = pop_many(&mut stack, token.span, count)?;
+
+                    let last = arguments
+                        .last()
+                        .map_or(token.span, |argument| argument.span);
+
+                    // like `run`, from the name to the last argument
+                    token.span.from_self_to_other(last).over(Expr::Call {
+                        function: id,
+                        arguments,
+                    })
+                }
+                Operator::Define { name, params, body } => {
+                    let params = pop_many(&mut stack, token.span, params)?
+                        .into_iter()
+                        .map(|param| match param.inner {
+                            Expr::Ident(id) => Ok(param.span.over(id)),
+                            _ => Err(param.span.over(TokenError::ProcessorError(
+                                ProcessorError::DidNotExpectExpression,
+                            ))),
+                        })
+                        .collect::<Result<_, _>>()?;
+
+                    let body = build_statements(&mut (&mut *tokens).take(body))?
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| {
+                            token.span.over(TokenError::ProcessorError(
+                                ProcessorError::ExpectedExpression,
+                            ))
+                        })?;
+
+                    token.span.over(Expr::Define {
+                        name,
+                        params,
+                        body: Box::new(body),
+                    })
+                }
+                Operator::Semicolon => {
+                    statements.append(&mut stack);
+                    continue;
+                }
+            },
+        };
+
+        stack.push(node);
+    }
+
+    statements.append(&mut stack);
+
+    Ok(statements)
+}
+
+/// Lowers a tree back into the tokens `run` takes, operators spanning their whole node.
+pub fn lower(node: &Spanned<Expr>) -> Vec<Spanned<Token>> {
+    let mut tokens = Vec::new();
+
+    lower_into(node, &mut tokens);
+
+    tokens
+}
+
+fn lower_into(node: &Spanned<Expr>, tokens: &mut Vec<Spanned<Token>>) {
+    let operator = |operator| node.span.over(Token::Operator(operator));
+
+    match &node.inner {
+        Expr::Number(num) => tokens.push(node.span.over(Token::Value(Value::Number(*num)))),
+        Expr::Ident(id) => tokens.push(node.span.over(Token::Value(Value::Ident(*id)))),
+        Expr::History(index) => tokens.push(node.span.over(Token::Value(Value::History(*index)))),
+        Expr::Unary { op, operand } => {
+            lower_into(operand, tokens);
+            tokens.push(operator(match op {
+                UnaryOp::Neg => Operator::Neg,
+                UnaryOp::Pos => Operator::Pos,
+            }));
+        }
+        Expr::Binary { op, left, right } => {
+            lower_into(left, tokens);
+            lower_into(right, tokens);
+            tokens.push(operator(op.operator()));
+        }
+        Expr::Assign { target, value } => {
+            lower_into(target, tokens);
+            lower_into(value, tokens);
+            tokens.push(operator(Operator::Equals));
+        }
+        Expr::Print(operand) => {
+            lower_into(operand, tokens);
+            tokens.push(operator(Operator::Print));
+        }
+        Expr::Call {
+            function,
+            arguments,
+        } => match arguments.is_empty() {
+            true => tokens.push(node.span.over(Token::Value(Value::Call(*function)))),
+            false => {
+                for (i, argument) in arguments.iter().enumerate() {
+                    lower_into(argument, tokens);
+
+                    if i > 0 {
+                        tokens.push(operator(Operator::Comma));
+                    }
+                }
+
+                tokens.push(operator(Operator::Call(*function, arguments.len())));
+            }
+        },
+        Expr::Define { name, params, body } => {
+            for param in params {
+                tokens.push(param.span.over(Token::Value(Value::Ident(param.inner))));
+            }
+
+            let body = lower(body);
+
+            tokens.push(operator(Operator::Define {
+                name: *name,
+                params: params.len(),
+                body: body.len(),
+            }));
+            tokens.extend(body);
+        }
+        Expr::Seq(statements) => {
+            for (i, statement) in statements.iter().enumerate() {
+                if i > 0 {
+                    tokens.push(operator(Operator::Semicolon));
+                }
+
+                lower_into(statement, tokens);
+            }
+        }
+    }
+}
+
+/// Evaluates a tree the same way `run` evaluates its tokens, returning the result of the last
+/// statement that had one.
+pub fn evaluate(
+    state: &mut State,
+    program: &Spanned<Expr>,
+) -> Result<Option<f64>, Spanned<RunErrorContainer>> {
+    let statements = match &program.inner {
+        Expr::Seq(statements) => statements.as_slice(),
+        _ => slice::from_ref(program),
+    };
+
+    transaction(state, |state| evaluate_statements(state, statements))
+}
+
+fn evaluate_statements(
+    state: &mut State,
+    statements: &[Spanned<Expr>],
+) -> Result<Option<f64>, Spanned<RunErrorContainer>> {
+    let mut result = None;
+
+    for statement in statements {
+        let entry = eval(state, statement)?;
+
+        if let Some(value) = end_statement(state, Some(entry))? {
+            result = Some(value);
+
+            let silent = matches!(statement.inner, Expr::Assign { .. } | Expr::Print(_));
+
+            if state.echo && !silent {
+                state.output.print(value, state.radix);
+            }
+        }
+
+        if state.atomicity == Atomicity::Statement {
+            state.commit();
+        }
+    }
+
+    Ok(result)
+}
+
+/// Evaluates a node to the stack entry `run` would leave for it, spanning the whole node.
+fn eval(
+    state: &mut State,
+    node: &Spanned<Expr>,
+) -> Result<Spanned<Stack>, Spanned<RunErrorContainer>> {
+    match &node.inner {
+        Expr::Number(num) => Ok(node.span.over(Stack::Value(*num))),
+        Expr::Ident(id) => Ok(node.span.over(Stack::Ident(*id))),
+        Expr::History(index) => history(state, node.span, *index),
+        Expr::Unary { op, operand } => {
+            let operand = eval(state, operand)?;
+            let num = number(state, operand)?;
+
+            Ok(node.span.over(Stack::Value(match op {
+                UnaryOp::Neg => -num.inner,
+                UnaryOp::Pos => num.inner,
+            })))
+        }
+        // Like the stack, both sides are evaluated before either is resolved to a number, right
+        // first, so `a + (a = 2)` and unassigned variable errors match `run`
+        Expr::Binary { op, left, right } => {
+            let left = eval(state, left)?;
+            let right = eval(state, right)?;
+
+            let right = number(state, right)?;
+            let left = number(state, left)?;
+
+            binary(state, op.operator(), left, right)
+        }
+        Expr::Assign { target, value } => {
+            let target = eval(state, target)?;
+            let value = eval(state, value)?;
+
+            let value = number(state, value)?;
+
+            assign(state, target, value)
+        }
+        Expr::Print(operand) => {
+            let operand = eval(state, operand)?;
+
+            print(state, node.span, operand)
+        }
+        Expr::Call {
+            function,
+            arguments,
+        } => {
+            let mut entries = Vec::with_capacity(arguments.len());
+
+            for argument in arguments {
+                entries.push(eval(state, argument)?);
+            }
+
+            let mut numbers = Vec::with_capacity(entries.len());
+
+            for entry in entries.into_iter().rev() {
+                numbers.push(number(state, entry)?.inner);
+            }
+
+            numbers.reverse();
+
+            call(state, node.span.over(*function), &numbers)
+        }
+        Expr::Define { name, params, body } => {
+            state.set_function(
+                *name,
+                Function::User(Rc::new(UserFunction {
+                    params: params.iter().map(|param| param.inner).collect(),
+                    body: lower(body),
+                })),
+            );
+
+            Ok(node.span.over(Stack::Null))
+        }
+        Expr::Seq(statements) => {
+            let mut entry = node.span.over(Stack::Null);
+
+            for statement in statements {
+                entry = eval(state, statement)?;
+            }
+
+            Ok(entry)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parsr::{input::StrView, interner::Interner};
+
+    use crate::{
+        output::Buffer,
+        raw_token::parse_raw_tokens,
+        run::{NumericPolicy, run},
+        tokens::resolved_tokens,
+    };
+
+    use super::*;
+
+    fn tokens(interner: &mut Interner, source: &str) -> Vec<TokenResult> {
+        let mut view = StrView::new(source);
+
+        resolved_tokens(parse_raw_tokens(&mut view, interner).unwrap()).collect()
+    }
+
+    #[test]
+    fn tree() {
+        let mut interner = Interner::new();
+
+        let x = interner.insert("x");
+        let max = interner.insert("max");
+
+        let tree = build(tokens(&mut interner, "x = -max(1, 2) ^ 2\n").into_iter()).unwrap();
+
+        let Expr::Seq(statements) = tree.inner else {
+            panic!("expected a sequence");
+        };
+
+        let Expr::Assign { target, value } = &statements[0].inner else {
+            panic!("expected an assignment");
+        };
+
+        assert_eq!(target.inner, Expr::Ident(x));
+        assert_eq!((statements[0].span.start, statements[0].span.end), (0, 18));
+
+        let Expr::Unary {
+            op: UnaryOp::Neg,
+            operand,
+        } = &value.inner
+        else {
+            panic!("expected a negation");
+        };
+
+        let Expr::Binary {
+            op: BinaryOp::Pow,
+            left,
+            ..
+        } = &operand.inner
+        else {
+            panic!("expected a power");
+        };
+
+        assert!(
+            matches!(&left.inner, Expr::Call { function, arguments } if *function == max && arguments.len() == 2)
+        );
+        assert_eq!((operand.span.start, operand.span.end), (5, 18));
+    }
+
+    #[test]
+    fn malformed_tokens() {
+        let f = unsafe { Id::from_usize(0) };
+
+        let expected = Err(Spanned::default_span(TokenError::ProcessorError(
+            ProcessorError::ExpectedExpression,
+        )));
+
+        for tokens in [
+            vec![Token::Operator(Operator::Add)],
+            vec![
+                Token::Value(Value::Number(1.0)),
+                Token::Operator(Operator::Equals),
+            ],
+            vec![Token::Operator(Operator::Call(f, 2))],
+            vec![Token::Operator(Operator::Define {
+                name: f,
+                params: 0,
+                body: 0,
+            })],
+        ] {
+            let tree = build(
+                tokens
+                    .into_iter()
+                    .map(|token| Ok(Spanned::default_span(token))),
+            );
+
+            assert_eq!(tree, expected);
+        }
+
+        // define f(1) = 1
+        let tokens = [
+            Token::Value(Value::Number(1.0)),
+            Token::Operator(Operator::Define {
+                name: f,
+                params: 1,
+                body: 1,
+            }),
+            Token::Value(Value::Number(1.0)),
+        ];
+
+        assert_eq!(
+            build(
+                tokens
+                    .into_iter()
+                    .map(|token| Ok(Spanned::default_span(token)))
+            ),
+            Err(Spanned::default_span(TokenError::ProcessorError(
+                ProcessorError::DidNotExpectExpression
+            )))
+        );
+    }
+
+    #[test]
+    fn lowering() {
+        let mut interner = Interner::new();
+
+        for source in [
+            "a = 1 + 2 * 3; % a ^ -2\n",
+            "f(x, y) = x * y + 1; f(2, max(1, 2, 3)) - g()\n",
+            "b = _1 / +4\n",
+        ] {
+            let tokens = tokens(&mut interner, source)
+                .into_iter()
+                .map(|token| token.unwrap().inner)
+                .collect::<Vec<_>>();
+
+            let tree = build(tokens.iter().map(|&token| Ok(Spanned::default_span(token)))).unwrap();
+
+            let lowered = lower(&tree)
+                .into_iter()
+                .map(|token| token.inner)
+                .collect::<Vec<_>>();
+
+            assert_eq!(lowered, tokens, "{source:?}");
+        }
+    }
+
+    #[test]
+    fn matches_run() {
+        let sources = [
+            "a = 2; b = a * 3 + 1; % b / 2\n",
+            "-2 ^ 2 + 2 ^ -1\n",
+            "a = 1; a + (a = 5)\n",
+            "f(x, y) = x * y + a; f(2, 3) + f(a, hypot(3, 4))\n",
+            "g(n) = g(n - 1); g(1)\n",
+            "x + y\n",
+            "1 = 2\n",
+            "1 + 2; _1 * 10; _5\n",
+            "max(1, c, d)\n",
+            "1 / 0; -1 / 0\n",
+            "sqrt(1, 2)\n",
+            "% 1; % q\n",
+            "h(x) = x; h = 2; h(h)\n",
+            "(a = 1; a = 2)\n",
+            "(1; 2) + 3\n",
+        ];
+
+        for source in sources {
+            for policy in [NumericPolicy::Ieee, NumericPolicy::Strict] {
+                for atomicity in [Atomicity::Off, Atomicity::Line, Atomicity::Statement] {
+                    let mut interner = Interner::new();
+
+                    let mut states = [(); 2].map(|()| {
+                        let mut state = State::with_builtins(&mut interner);
+
+                        let buffer = Buffer::default();
+
+                        state.output = Box::new(buffer.clone());
+                        state.numeric = policy;
+                        state.atomicity = atomicity;
+                        state.echo = true;
+                        state.ans = Some(interner.insert("ans"));
+                        state.record_history = true;
+
+                        (state, buffer)
+                    });
+
+                    let tokens = tokens(&mut interner, source);
+
+                    let [(run_state, run_output), (tree_state, tree_output)] = &mut states;
+
+                    // a source that does not resolve must fail the same way under `run`
+                    let tree = match build(tokens.iter().cloned()) {
+                        Ok(tree) => tree,
+                        Err(err) => {
+                            assert_eq!(
+                                run(run_state, tokens.into_iter()),
+                                Err(err.map(RunErrorContainer::TokenError)),
+                                "{source:?}"
+                            );
+                            continue;
+                        }
+                    };
+
+                    let from_run = run(run_state, tokens.into_iter());
+                    let from_tree = evaluate(tree_state, &tree);
+
+                    let context = format!("{source:?} {policy:?} {atomicity:?}");
+
+                    assert_eq!(from_run, from_tree, "{context}");
+                    assert_eq!(run_output.take(), tree_output.take(), "{context}");
+                    assert_eq!(run_state.history, tree_state.history, "{context}");
+
+                    let mut run_vars = run_state.variables.iter().collect::<Vec<_>>();
+                    let mut tree_vars = tree_state.variables.iter().collect::<Vec<_>>();
+
+                    run_vars.sort_by_key(|(id, _)| format!("{id:?}"));
+                    tree_vars.sort_by_key(|(id, _)| format!("{id:?}"));
+
+                    assert_eq!(run_vars, tree_vars, "{context}");
+                }
+            }
+        }
+    }
+}
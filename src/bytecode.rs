@@ -0,0 +1,551 @@
+use std::rc::Rc;
+
+use parsr::{
+    interner::Id,
+    token::span::{Span, Spanned},
+};
+
+use crate::{
+    ast::{BinaryOp, Expr, UnaryOp, lower},
+    functions::{Function, UserFunction},
+    run::{RunError, RunErrorContainer, Stack, State, binary, call, history},
+    tokens::{ProcessorError, TokenError},
+};
+
+/// A single instruction, run against the stack of a `Vm`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Number(f64),
+    /// Pushes a variable, resolved to its value only when an operator takes it
+    Slot(usize),
+    History(usize),
+    /// Calls a function with the argument count, `0` for `f()`
+    Call(Id, usize),
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    Assign,
+    Print,
+    Define(Id, Rc<UserFunction>),
+    /// Ends a statement, echoing its result unless it is an assignment or print
+    End {
+        silent: bool,
+    },
+}
+
+/// A program lowered to instructions, its variables numbered as slots.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Chunk {
+    /// Each instruction, spanning the source of the node it was lowered from
+    pub code: Vec<Spanned<Op>>,
+    /// The variable of each slot
+    pub slots: Vec<Id>,
+}
+
+impl Chunk {
+    /// Finds the slot of a variable, if the chunk uses it.
+    pub fn slot(&self, id: Id) -> Option<usize> {
+        self.slots.iter().position(|&slot| slot == id)
+    }
+
+    fn slot_or_insert(&mut self, id: Id) -> usize {
+        self.slot(id).unwrap_or_else(|| {
+            self.slots.push(id);
+            self.slots.len() - 1
+        })
+    }
+}
+
+/// Lowers a tree from `ast::build` to bytecode.
+pub fn compile(program: &Spanned<Expr>) -> Chunk {
+    let mut chunk = Chunk::default();
+
+    match &program.inner {
+        Expr::Seq(statements) => {
+            for statement in statements {
+                compile_statement(&mut chunk, statement);
+            }
+        }
+        _ => compile_statement(&mut chunk, program),
+    }
+
+    chunk
+}
+
+fn compile_statement(chunk: &mut Chunk, statement: &Spanned<Expr>) {
+    compile_into(chunk, statement);
+
+    let silent = matches!(statement.inner, Expr::Assign { .. } | Expr::Print(_));
+
+    chunk.code.push(statement.span.over(Op::End { silent }));
+}
+
+fn compile_into(chunk: &mut Chunk, node: &Spanned<Expr>) {
+    let op = match &node.inner {
+        Expr::Number(num) => Op::Number(*num),
+        Expr::Ident(id) => Op::Slot(chunk.slot_or_insert(*id)),
+        Expr::History(index) => Op::History(*index),
+        Expr::Unary { op, operand } => {
+            compile_into(chunk, operand);
+            Op::Unary(*op)
+        }
+        Expr::Binary { op, left, right } => {
+            compile_into(chunk, left);
+            compile_into(chunk, right);
+            Op::Binary(*op)
+        }
+        Expr::Assign { target, value } => {
+            compile_into(chunk, target);
+            compile_into(chunk, value);
+            Op::Assign
+        }
+        Expr::Print(operand) => {
+            compile_into(chunk, operand);
+            Op::Print
+        }
+        Expr::Call {
+            function,
+            arguments,
+        } => {
+            for argument in arguments {
+                compile_into(chunk, argument);
+            }
+
+            Op::Call(*function, arguments.len())
+        }
+        Expr::Define { name, params, body } => Op::Define(
+            *name,
+            Rc::new(UserFunction {
+                params: params.iter().map(|param| param.inner).collect(),
+                body: lower(body),
+            }),
+        ),
+        Expr::Seq(statements) => {
+            for statement in statements {
+                compile_statement(chunk, statement);
+            }
+
+            return;
+        }
+    };
+
+    chunk.code.push(node.span.over(op));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Entry {
+    Value(f64),
+    Slot(usize),
+    Null,
+}
+
+/// Runs chunks, keeping the value of each slot between runs.
+///
+/// Slots are separate from `State::variables`: `load` and `store` copy between the two. User
+/// functions read globals from the state, so the slots are stored before calling one.
+#[derive(Debug, Clone, Default)]
+pub struct Vm {
+    slots: Vec<Option<f64>>,
+    /// The variable of each slot in the state when `load_changed` last ran
+    globals: Option<Vec<Option<f64>>>,
+    stack: Vec<Spanned<Entry>>,
+}
+
+impl Vm {
+    pub fn new(chunk: &Chunk) -> Self {
+        Self {
+            slots: vec![None; chunk.slots.len()],
+            globals: None,
+            stack: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, slot: usize) -> Option<f64> {
+        self.slots.get(slot).copied().flatten()
+    }
+
+    pub fn set(&mut self, slot: usize, value: f64) {
+        if slot >= self.slots.len() {
+            self.slots.resize(slot + 1, None);
+        }
+
+        self.slots[slot] = Some(value);
+    }
+
+    /// Sets every slot to its variable in `state`, or unassigned.
+    pub fn load(&mut self, chunk: &Chunk, state: &State) {
+        self.slots = chunk
+            .slots
+            .iter()
+            .map(|id| state.variables.get(id).copied())
+            .collect();
+    }
+
+    /// Sets every unassigned slot to its variable in `state`, and every slot whose variable has
+    /// changed since this last ran. Other slots keep their value, whether set with `set` or by
+    /// an assignment.
+    pub fn load_changed(&mut self, chunk: &Chunk, state: &State) {
+        if self.slots.len() < chunk.slots.len() {
+            self.slots.resize(chunk.slots.len(), None);
+        }
+
+        let first = self.globals.is_none();
+        let globals = self.globals.get_or_insert_default();
+
+        if globals.len() < chunk.slots.len() {
+            globals.resize(chunk.slots.len(), None);
+        }
+
+        for ((slot, seen), id) in self.slots.iter_mut().zip(globals).zip(&chunk.slots) {
+            let global = state.variables.get(id).copied();
+
+            let changed = global.map(f64::to_bits) != seen.map(f64::to_bits);
+
+            if slot.is_none() || (changed && global.is_some() && !first) {
+                *slot = global;
+            }
+
+            *seen = global;
+        }
+    }
+
+    /// Writes every assigned slot back to its variable in `state`.
+    pub fn store(&self, chunk: &Chunk, state: &mut State) {
+        for (&id, &value) in chunk.slots.iter().zip(&self.slots) {
+            if let Some(value) = value {
+                state.variables.insert(id, value);
+            }
+        }
+    }
+
+    fn number(&self, entry: Spanned<Entry>) -> Result<Spanned<f64>, Spanned<RunErrorContainer>> {
+        let num = match entry.inner {
+            Entry::Value(num) => num,
+            Entry::Slot(slot) => match self.get(slot) {
+                Some(num) => num,
+                None => {
+                    return Err(entry
+                        .span
+                        .over(RunErrorContainer::RunError(RunError::UnassignedVariable)));
+                }
+            },
+            Entry::Null => {
+                return Err(entry
+                    .span
+                    .over(RunErrorContainer::RunError(RunError::AttemptedToUseNull)));
+            }
+        };
+
+        Ok(entry.span.over(num))
+    }
+
+    fn pop(&mut self, span: Span) -> Result<Spanned<Entry>, Spanned<RunErrorContainer>> {
+        self.stack.pop().ok_or_else(|| {
+            span.over(RunErrorContainer::TokenError(TokenError::ProcessorError(
+                ProcessorError::ExpectedExpression,
+            )))
+        })
+    }
+
+    fn pop_number(&mut self, span: Span) -> Result<Spanned<f64>, Spanned<RunErrorContainer>> {
+        let entry = self.pop(span)?;
+
+        self.number(entry)
+    }
+
+    fn push(&mut self, entry: Spanned<Stack>) {
+        self.stack.push(entry.map(|inner| match inner {
+            Stack::Value(num) => Entry::Value(num),
+            Stack::Null => Entry::Null,
+            Stack::Ident(_) => unreachable!("calls and arithmetic only return values"),
+        }));
+    }
+
+    /// Runs a chunk with the same semantics as `run::run` with `Atomicity::Off`, returning the
+    /// result of the last statement that had one. Results are never added to `State::history`,
+    /// since a chunk is meant to be run many times.
+    pub fn run(
+        &mut self,
+        chunk: &Chunk,
+        state: &mut State,
+    ) -> Result<Option<f64>, Spanned<RunErrorContainer>> {
+        if self.slots.len() < chunk.slots.len() {
+            self.slots.resize(chunk.slots.len(), None);
+        }
+
+        self.stack.clear();
+
+        let ans = state.ans.and_then(|ans| chunk.slot(ans));
+
+        let mut result = None;
+
+        for op in &chunk.code {
+            let span = op.span;
+
+            match &op.inner {
+                Op::Number(num) => self.stack.push(span.over(Entry::Value(*num))),
+                Op::Slot(slot) => self.stack.push(span.over(Entry::Slot(*slot))),
+                Op::History(index) => self.push(history(state, span, *index)?),
+                Op::Call(id, count) => {
+                    let mut arguments = vec![0.0; *count];
+
+                    for argument in arguments.iter_mut().rev() {
+                        *argument = self.pop_number(span)?.inner;
+                    }
+
+                    if let Some(Function::User(_)) = state.functions.get(id) {
+                        self.store(chunk, state);
+                    }
+
+                    self.push(call(state, span.over(*id), &arguments)?);
+                }
+                Op::Unary(op) => {
+                    let num = self.pop_number(span)?;
+
+                    self.stack.push(span.over(Entry::Value(match op {
+                        UnaryOp::Neg => -num.inner,
+                        UnaryOp::Pos => num.inner,
+                    })));
+                }
+                Op::Binary(op) => {
+                    let right = self.pop_number(span)?;
+                    let left = self.pop_number(span)?;
+
+                    self.push(binary(state, op.operator(), left, right)?);
+                }
+                Op::Assign => {
+                    let value = self.pop_number(span)?;
+                    let target = self.pop(span)?;
+
+                    let slot = match target.inner {
+                        Entry::Slot(slot) => slot,
+                        Entry::Value(_) => {
+                            return Err(target.span.over(RunErrorContainer::RunError(
+                                RunError::AssigningToExpression,
+                            )));
+                        }
+                        Entry::Null => {
+                            return Err(target
+                                .span
+                                .over(RunErrorContainer::RunError(RunError::AssigningToNull)));
+                        }
+                    };
+
+                    self.set(slot, value.inner);
+
+                    self.stack.push(
+                        target
+                            .span
+                            .from_self_to_other(value.span)
+                            .over(Entry::Slot(slot)),
+                    );
+                }
+                Op::Print => {
+                    let entry = self.pop(span)?;
+
+                    if entry.inner == Entry::Null {
+                        return Err(entry
+                            .span
+                            .over(RunErrorContainer::RunError(RunError::AttemptedToPrintNull)));
+                    }
+
+                    let value = self.number(entry)?;
+
+                    state.output.print(value.inner, state.radix);
+
+                    self.stack
+                        .push(span.from_self_to_other(entry.span).over(entry.inner));
+                }
+                Op::Define(name, function) => {
+                    state
+                        .functions
+                        .insert(*name, Function::User(Rc::clone(function)));
+
+                    self.stack.push(span.over(Entry::Null));
+                }
+                Op::End { silent } => {
+                    let entry = self.pop(span)?;
+
+                    if entry.inner != Entry::Null {
+                        let value = self.number(entry)?.inner;
+
+                        result = Some(value);
+
+                        if let Some(id) = state.ans {
+                            state.set_variable(id, value);
+                        }
+
+                        if let Some(ans) = ans {
+                            self.slots[ans] = Some(value);
+                        }
+
+                        if state.echo && !silent {
+                            state.output.print(value, state.radix);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parsr::{input::StrView, interner::Interner};
+
+    use crate::{
+        ast::build,
+        output::Buffer,
+        raw_token::parse_raw_tokens,
+        run::{NumericPolicy, run},
+        tokens::resolved_tokens,
+    };
+
+    use super::*;
+
+    fn state(interner: &mut Interner, policy: NumericPolicy) -> (State, Buffer) {
+        let mut state = State::with_builtins(interner);
+
+        let buffer = Buffer::default();
+
+        state.output = Box::new(buffer.clone());
+        state.numeric = policy;
+        state.echo = true;
+        state.ans = Some(interner.insert("ans"));
+
+        (state, buffer)
+    }
+
+    #[test]
+    fn matches_run() {
+        let sources = [
+            "a = 2; b = a * 3 + 1; % b / 2\n",
+            "-2 ^ 2 + 2 ^ -1\n",
+            "a = 1; a + (a = 5)\n",
+            "a = 3; f(x, y) = x * y + a; f(2, 3) + f(a, hypot(3, 4))\n",
+            "g(n) = g(n - 1); g(1)\n",
+            "x + y\n",
+            "1 = 2\n",
+            "1 + 2; _1 * 10; ans + _5\n",
+            "max(1, c, d)\n",
+            "1 / 0; -1 / 0\n",
+            "sqrt(1, 2)\n",
+            "% 1; % q\n",
+            "% p = 4; p\n",
+            "h(x) = x; h = 2; h(h)\n",
+            "(a = 1; a = 2)\n",
+            "(1; 2) + 3\n",
+        ];
+
+        for source in sources {
+            for policy in [NumericPolicy::Ieee, NumericPolicy::Strict] {
+                let mut interner = Interner::new();
+
+                let (mut run_state, run_output) = state(&mut interner, policy);
+                let (mut vm_state, vm_output) = state(&mut interner, policy);
+
+                let mut view = StrView::new(source);
+
+                let tokens = resolved_tokens(parse_raw_tokens(&mut view, &mut interner).unwrap())
+                    .collect::<Vec<_>>();
+
+                // a source that does not resolve must fail the same way under `run`
+                let tree = match build(tokens.iter().cloned()) {
+                    Ok(tree) => tree,
+                    Err(err) => {
+                        assert_eq!(
+                            run(&mut run_state, tokens.into_iter()),
+                            Err(err.map(RunErrorContainer::TokenError)),
+                            "{source:?}"
+                        );
+                        continue;
+                    }
+                };
+
+                let chunk = compile(&tree);
+
+                let mut vm = Vm::new(&chunk);
+
+                let from_run = run(&mut run_state, tokens.into_iter());
+                let from_vm = vm.run(&chunk, &mut vm_state);
+
+                vm.store(&chunk, &mut vm_state);
+
+                let context = format!("{source:?} {policy:?}");
+
+                assert_eq!(from_run, from_vm, "{context}");
+                assert_eq!(run_output.take(), vm_output.take(), "{context}");
+                assert_eq!(run_state.history, vm_state.history, "{context}");
+
+                for (id, value) in &run_state.variables {
+                    assert_eq!(vm_state.variables.get(id), Some(value), "{context}");
+                }
+
+                assert_eq!(
+                    run_state.variables.len(),
+                    vm_state.variables.len(),
+                    "{context}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn slots() {
+        let mut interner = Interner::new();
+        let mut state = State::with_builtins(&mut interner);
+        state.record_history = true;
+
+        let mut view = StrView::new("y = x * x + 1; y / 2\n");
+
+        let tokens = resolved_tokens(parse_raw_tokens(&mut view, &mut interner).unwrap());
+
+        let chunk = compile(&build(tokens).unwrap());
+
+        let x = chunk.slot(interner.insert("x")).unwrap();
+        let y = chunk.slot(interner.insert("y")).unwrap();
+
+        let mut vm = Vm::new(&chunk);
+
+        for value in [1.0, 2.0, 3.0] {
+            vm.set(x, value);
+
+            assert_eq!(
+                vm.run(&chunk, &mut state),
+                Ok(Some((value * value + 1.0) / 2.0))
+            );
+            assert_eq!(vm.get(y), Some(value * value + 1.0));
+        }
+
+        assert!(state.variables.is_empty());
+        assert_eq!(state.history, vec![]);
+
+        vm.store(&chunk, &mut state);
+        assert_eq!(state.variables.get(&interner.insert("y")), Some(&10.0));
+    }
+
+    #[test]
+    fn malformed_chunk() {
+        let mut interner = Interner::new();
+        let (mut state, _) = state(&mut interner, NumericPolicy::Ieee);
+
+        let expected = Err(Spanned::default_span(RunErrorContainer::TokenError(
+            TokenError::ProcessorError(ProcessorError::ExpectedExpression),
+        )));
+
+        for code in [
+            vec![Op::Binary(BinaryOp::Add)],
+            vec![Op::Number(1.0), Op::Assign],
+            vec![Op::Print],
+            vec![Op::End { silent: false }],
+        ] {
+            let chunk = Chunk {
+                code: code.into_iter().map(Spanned::default_span).collect(),
+                slots: Vec::new(),
+            };
+
+            assert_eq!(Vm::new(&chunk).run(&chunk, &mut state), expected);
+        }
+    }
+}
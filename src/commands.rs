@@ -0,0 +1,464 @@
+use std::{fmt::Display, fs, time::Instant};
+
+use calc_lang::{
+    ast::build,
+    format::format_tree,
+    optimize::optimize,
+    raw_token::{RawToken, parse_raw_tokens},
+    run::Radix,
+    tokens::{Operator, Token, Value, resolved_tokens},
+};
+use parsr::{input::StrView, interner::Interner, token::span::Spanned};
+
+use crate::{console::Session, script::script};
+
+/// Every meta-command with its arguments and a description, as listed by `:help`.
+pub const COMMANDS: [(&str, &str, &str); 12] = [
+    ("help", "", "Lists the meta-commands"),
+    ("q", "", "Quits the console"),
+    ("vars", "", "Lists the variables and their values"),
+    ("unset", "<name>", "Removes a variable or function"),
+    ("clear", "", "Removes every variable and function"),
+    ("load", "<file>", "Runs a script in this session"),
+    ("save", "<file>", "Writes the lines run so far to a script"),
+    (
+        "tokens",
+        "<expr>",
+        "Shows the tokens an expression lexes into",
+    ),
+    (
+        "rpn",
+        "<expr>",
+        "Shows the order an expression is evaluated in",
+    ),
+    (
+        "opt",
+        "<expr>",
+        "Shows an expression with constants folded and identities removed",
+    ),
+    (
+        "time",
+        "<expr>",
+        "Evaluates an expression and shows how long it took",
+    ),
+    (
+        "radix",
+        "<2|8|10|16>",
+        "Sets the base values are printed in",
+    ),
+];
+
+/// What the console should do after a meta-command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CommandError {
+    UnknownCommand,
+    ExpectedArgument,
+    UnknownName,
+    InvalidRadix,
+    Io(String),
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::UnknownCommand => write!(f, "Unknown command, try :help"),
+            CommandError::ExpectedArgument => write!(f, "Expected an argument"),
+            CommandError::UnknownName => write!(f, "No variable or function has this name"),
+            CommandError::InvalidRadix => write!(f, "Expected a radix of 2, 8, 10 or 16"),
+            CommandError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+fn spanned<T>(inner: T, start: usize, end: usize) -> Spanned<T> {
+    let mut spanned = Spanned::default_span(inner);
+
+    spanned.span.start = start;
+    spanned.span.end = end;
+
+    spanned
+}
+
+/// Runs a line starting with `:`, with spans counted in chars from the start of the line.
+pub fn command(session: &mut Session, line: &str) -> Result<Flow, Spanned<CommandError>> {
+    let line = line.trim_end();
+
+    let (name, argument) = line[1..]
+        .split_once(char::is_whitespace)
+        .unwrap_or((&line[1..], ""));
+
+    let name_end = 1 + name.chars().count();
+
+    let argument = argument.trim_start();
+    let argument_start = line.chars().count() - argument.chars().count();
+    let argument_end = argument_start + argument.chars().count();
+
+    let expect_argument = || match argument.is_empty() {
+        true => Err(spanned(CommandError::ExpectedArgument, 0, name_end)),
+        false => Ok(argument),
+    };
+
+    let io_error = |e: std::io::Error| {
+        spanned(
+            CommandError::Io(e.to_string()),
+            argument_start,
+            argument_end,
+        )
+    };
+
+    match name {
+        "q" => return Ok(Flow::Quit),
+        "help" => {
+            for (name, arguments, description) in COMMANDS {
+                let usage = format!(":{name} {arguments}");
+
+                session
+                    .engine
+                    .state
+                    .output
+                    .line(&format!("{usage: <20}{description}"));
+            }
+        }
+        "vars" => {
+            let mut variables = session
+                .engine
+                .state
+                .variables
+                .iter()
+                .map(|(&id, &value)| (session.engine.interner.get(id), value))
+                .collect::<Vec<_>>();
+
+            variables.sort_by(|a, b| a.0.cmp(b.0));
+
+            for (name, value) in variables {
+                let value = session.engine.state.radix.format(value);
+
+                session
+                    .engine
+                    .state
+                    .output
+                    .line(&format!("{name} = {value}"));
+            }
+        }
+        "unset" => {
+            let name = expect_argument()?;
+
+            let Some(id) = session.engine.id_of(name) else {
+                return Err(spanned(
+                    CommandError::UnknownName,
+                    argument_start,
+                    argument_end,
+                ));
+            };
+
+            let variable = session.engine.state.variables.remove(&id);
+
+            let function = session.engine.state.remove_function(id);
+
+            if variable.is_none() && function.is_none() {
+                return Err(spanned(
+                    CommandError::UnknownName,
+                    argument_start,
+                    argument_end,
+                ));
+            }
+        }
+        "clear" => {
+            session.engine.state.clear();
+            session.transcript.clear();
+        }
+        "load" => {
+            let path = expect_argument()?;
+
+            let source = fs::read_to_string(path).map_err(io_error)?;
+
+            if script(&mut session.engine, path, &source) {
+                session.transcript.push(source);
+            }
+        }
+        "save" => {
+            let path = expect_argument()?;
+
+            fs::write(path, session.transcript.concat()).map_err(io_error)?;
+        }
+        "tokens" => {
+            let source = format!("{}\n", expect_argument()?);
+
+            let mut view = StrView::new(&source);
+
+            let tokens = parse_raw_tokens(&mut view, &mut session.engine.interner)
+                .map_err(|_| spanned(CommandError::Io("Invalid UTF-8".to_owned()), 0, 0))?
+                .collect::<Vec<_>>();
+
+            for token in tokens {
+                let (line, failed) = match token {
+                    Ok(token) => (
+                        format!(
+                            "{}..{}\t{}",
+                            token.span.start,
+                            token.span.end,
+                            describe_raw_token(token.inner, &session.engine.interner)
+                        ),
+                        false,
+                    ),
+                    Err(err) => {
+                        let err = err.spanned();
+
+                        (
+                            format!("{}..{}\t{}", err.span.start, err.span.end, err.inner),
+                            true,
+                        )
+                    }
+                };
+
+                session.engine.state.output.line(&line);
+
+                if failed {
+                    break;
+                }
+            }
+        }
+        "rpn" => {
+            let source = format!("{}\n", expect_argument()?);
+
+            let mut view = StrView::new(&source);
+
+            let raw_tokens = parse_raw_tokens(&mut view, &mut session.engine.interner)
+                .map_err(|_| spanned(CommandError::Io("Invalid UTF-8".to_owned()), 0, 0))?;
+
+            let tokens = resolved_tokens(raw_tokens).collect::<Vec<_>>();
+
+            let mut order = Vec::new();
+
+            for token in tokens {
+                match token {
+                    Ok(token) => order.push(describe_token(token.inner, &session.engine.interner)),
+                    Err(err) => {
+                        order.push(format!("!{}", err.inner));
+                        break;
+                    }
+                }
+            }
+
+            session.engine.state.output.line(&order.join(" "));
+        }
+        "opt" => {
+            let source = format!("{}\n", expect_argument()?);
+
+            let mut view = StrView::new(&source);
+
+            let raw_tokens = parse_raw_tokens(&mut view, &mut session.engine.interner)
+                .map_err(|_| spanned(CommandError::Io("Invalid UTF-8".to_owned()), 0, 0))?;
+
+            match build(resolved_tokens(raw_tokens)) {
+                Ok(tree) => {
+                    let optimized = optimize(tree, session.engine.state.numeric);
+
+                    let line = format_tree(&optimized, &session.engine.interner);
+
+                    session.engine.state.output.line(&line);
+                }
+                Err(err) => session.engine.state.output.line(&format!("!{}", err.inner)),
+            }
+        }
+        "time" => {
+            let source = expect_argument()?.to_owned();
+
+            let start = Instant::now();
+
+            session.eval(&source);
+
+            let elapsed = start.elapsed();
+
+            session
+                .engine
+                .state
+                .output
+                .line(&format!("took {elapsed:?}"));
+        }
+        "radix" => match Radix::parse(argument) {
+            Some(radix) => session.engine.state.radix = radix,
+            None => {
+                return Err(spanned(
+                    CommandError::InvalidRadix,
+                    argument_start,
+                    argument_end,
+                ));
+            }
+        },
+        _ => return Err(spanned(CommandError::UnknownCommand, 0, name_end)),
+    }
+
+    Ok(Flow::Continue)
+}
+
+fn describe_raw_token(token: RawToken, interner: &Interner) -> String {
+    match token {
+        RawToken::Ident(id) => format!("ident {}", interner.get(id)),
+        RawToken::Number(n) => format!("number {n}"),
+        RawToken::Symbol(symbol) => format!("symbol {symbol}"),
+        RawToken::History(index) => format!("history _{index}"),
+    }
+}
+
+/// Shows a resolved token, with calls as `name/arguments`.
+fn describe_token(token: Token, interner: &Interner) -> String {
+    match token {
+        Token::Value(Value::Number(n)) => n.to_string(),
+        Token::Value(Value::Ident(id)) => interner.get(id).to_owned(),
+        Token::Value(Value::Call(id)) => format!("{}/0", interner.get(id)),
+        Token::Value(Value::History(index)) => format!("_{index}"),
+        Token::Operator(operator) => match operator {
+            Operator::Equals => "=".to_owned(),
+            Operator::Add => "+".to_owned(),
+            Operator::Sub => "-".to_owned(),
+            Operator::Mul => "*".to_owned(),
+            Operator::Div => "/".to_owned(),
+            Operator::Pow => "^".to_owned(),
+            Operator::Neg => "neg".to_owned(),
+            Operator::Pos => "pos".to_owned(),
+            Operator::Print => "%".to_owned(),
+            Operator::Semicolon => ";".to_owned(),
+            Operator::Comma => ",".to_owned(),
+            Operator::Call(id, arguments) => format!("{}/{arguments}", interner.get(id)),
+            Operator::Define { name, params, .. } => {
+                format!("define {}/{params}", interner.get(name))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use calc_lang::{functions::Function, output::Buffer, run::NumericPolicy};
+
+    use super::*;
+
+    /// A session whose output is kept for the test to read.
+    fn session() -> (Session, Buffer) {
+        let mut session = Session::new();
+        let buffer = Buffer::default();
+
+        session.engine.state.output = Box::new(buffer.clone());
+
+        (session, buffer)
+    }
+
+    #[test]
+    fn commands() {
+        let (mut session, buffer) = session();
+
+        session.eval("a = 2; f(x) = x * a");
+
+        let a = session.engine.interner.insert("a");
+
+        assert_eq!(command(&mut session, ":vars\n"), Ok(Flow::Continue));
+        assert_eq!(buffer.take_lines(), ["a = 2", "ans = 2"]);
+
+        assert_eq!(command(&mut session, ":unset a\n"), Ok(Flow::Continue));
+        assert!(!session.engine.state.variables.contains_key(&a));
+
+        let err = command(&mut session, ":unset  a\n").unwrap_err();
+        assert_eq!(err.inner, CommandError::UnknownName);
+        assert_eq!((err.span.start, err.span.end), (8, 9));
+
+        let err = command(&mut session, ":unset nothing\n").unwrap_err();
+        assert_eq!(err.inner, CommandError::UnknownName);
+        assert_eq!(session.engine.id_of("nothing"), None);
+
+        assert_eq!(
+            command(&mut session, ":tokens a2 += \n"),
+            Ok(Flow::Continue)
+        );
+        assert_eq!(
+            buffer.take_lines(),
+            ["0..2\tident a2", "3..4\tsymbol +", "4..5\tsymbol ="]
+        );
+
+        let err = command(&mut session, ":frobnicate 1\n").unwrap_err();
+        assert_eq!(err.inner, CommandError::UnknownCommand);
+        assert_eq!((err.span.start, err.span.end), (0, 11));
+
+        let err = command(&mut session, ":load\n").unwrap_err();
+        assert_eq!(err.inner, CommandError::ExpectedArgument);
+
+        assert_eq!(command(&mut session, ":clear\n"), Ok(Flow::Continue));
+        let sqrt = session.engine.interner.insert("sqrt");
+        let f = session.engine.interner.insert("f");
+
+        assert!(session.engine.state.functions.contains_key(&sqrt));
+        assert!(!session.engine.state.functions.contains_key(&f));
+
+        // Removing a function that replaced a builtin brings the builtin back
+        session.eval("sqrt(x) = x");
+        assert_eq!(command(&mut session, ":unset sqrt\n"), Ok(Flow::Continue));
+        assert!(matches!(
+            session.engine.state.functions.get(&sqrt),
+            Some(Function::Builtin(_))
+        ));
+
+        session.eval("sqrt(x) = x");
+        assert_eq!(command(&mut session, ":clear\n"), Ok(Flow::Continue));
+        assert!(matches!(
+            session.engine.state.functions.get(&sqrt),
+            Some(Function::Builtin(_))
+        ));
+
+        let err = command(&mut session, ":unset sqrt\n").unwrap_err();
+        assert_eq!(err.inner, CommandError::UnknownName);
+
+        assert_eq!(command(&mut session, ":q\n"), Ok(Flow::Quit));
+    }
+
+    #[test]
+    fn rpn() {
+        let mut interner = Interner::new();
+
+        let source = "x = -max(1, 2) ^ 2\n";
+        let mut view = StrView::new(source);
+
+        let tokens = resolved_tokens(parse_raw_tokens(&mut view, &mut interner).unwrap())
+            .collect::<Vec<_>>();
+
+        let order = tokens
+            .into_iter()
+            .map(|token| describe_token(token.unwrap().inner, &interner))
+            .collect::<Vec<_>>();
+
+        assert_eq!(order.join(" "), "x 1 2 , max/2 2 ^ neg =");
+    }
+
+    #[test]
+    fn opt() {
+        let (mut session, buffer) = session();
+
+        assert_eq!(
+            command(&mut session, ":opt x * (2 * 3 + 4) * 1\n"),
+            Ok(Flow::Continue)
+        );
+        assert_eq!(buffer.take_lines(), ["x * 10"]);
+
+        assert_eq!(
+            command(&mut session, ":opt (1 - 3) ^ x + y * (1 - 3)\n"),
+            Ok(Flow::Continue)
+        );
+        assert_eq!(buffer.take_lines(), ["(-2) ^ x + y * -2"]);
+
+        session.engine.state.numeric = NumericPolicy::Strict;
+
+        assert_eq!(
+            command(&mut session, ":opt x * (2 - 1)\n"),
+            Ok(Flow::Continue)
+        );
+        assert_eq!(buffer.take_lines(), ["x * 1"]);
+
+        let err = command(&mut session, ":opt\n").unwrap_err();
+        assert_eq!(err.inner, CommandError::ExpectedArgument);
+    }
+}
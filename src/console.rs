@@ -1,50 +1,259 @@
-use std::io::{Write, stdin, stdout};
+use std::{
+    fmt::Display,
+    fs,
+    io::{ErrorKind, Write, stdout},
+    path::Path,
+};
 
+use calc_lang::{
+    Engine, Location,
+    raw_token::{RawToken, Symbol, parse_raw_tokens},
+    run::Atomicity,
+};
 use parsr::{input::StrView, interner::Interner};
+use rustyline::{Editor, error::ReadlineError, history::DefaultHistory};
 
 use crate::{
-    raw_token::parse_raw_tokens,
-    run::{State, run},
-    tokens::resolved_tokens,
+    commands::{Flow, command},
+    editor::{CalcHelper, history_path},
 };
 
-pub fn console() {
-    let mut interner = Interner::new();
-    let mut state = State::new();
+/// Everything a console session keeps between lines.
+pub struct Session {
+    pub engine: Engine,
+    /// The lines that ran successfully, for `:save`
+    pub transcript: Vec<String>,
+}
 
-    loop {
-        print!("< ");
+impl Session {
+    pub fn new() -> Self {
+        let mut engine = Engine::new();
+
+        // a line that errors should leave the session as it was
+        engine.state.atomicity = Atomicity::Line;
+        engine.state.ans = Some(engine.intern("ans"));
+        engine.state.echo = true;
+        engine.state.record_history = true;
+
+        Self {
+            engine,
+            transcript: Vec::new(),
+        }
+    }
 
+    /// Runs a line, printing its output and every error.
+    pub fn eval(&mut self, line: &str) {
+        print!("> ");
         stdout().flush().unwrap();
 
-        let mut line = String::new();
+        // nothing runs unless the whole line resolves
+        let result = self
+            .engine
+            .compile(line)
+            .and_then(|program| self.engine.run(&program).map_err(|err| vec![err]));
 
-        let Ok(_) = stdin().read_line(&mut line) else {
-            continue;
-        };
+        match result {
+            Ok(_) => self.transcript.push(format!("{}\n", line.trim_end())),
+            Err(diagnostics) => {
+                println!("\n");
 
-        if line.starts_with(":q") {
-            break;
+                for err in diagnostics {
+                    report(line, err.start, err.end, err.message);
+                }
+            }
         }
+    }
+}
 
-        print!("> ");
-        stdout().flush().unwrap();
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        let mut view = StrView::new(&line);
+/// Prints an error, underlining the chars from `start` to `end` of `source` on the line they
+/// start on.
+pub fn report(source: &str, start: usize, end: usize, message: impl Display) {
+    let location = Location::of(source, start);
 
-        let raw_tokens = parse_raw_tokens(&mut view, &mut interner).unwrap();
+    let line = source.lines().nth(location.line - 1).unwrap_or("");
 
-        let tokens = resolved_tokens(raw_tokens);
+    let column = location.column - 1;
+    let length = (end - start).clamp(1, line.chars().count().saturating_sub(column).max(1));
 
-        if let Err(err) = run(&mut state, tokens) {
-            print!("\n\n!> {line}!> ");
-            print!("{: <1$}", "", err.span.start);
-            println!("{:~<1$}", "", err.span.end - err.span.start);
-            print!("!> ");
-            print!("{: <1$}", "", err.span.start);
-            println!("^ ERROR: {}", err.inner);
+    if source.trim_end().contains('\n') {
+        println!("!> line {}:", location.line);
+    }
+
+    println!("!> {line}");
+    print!("!> ");
+    print!("{: <1$}", "", column);
+    println!("{:~<1$}", "", length);
+    print!("!> ");
+    print!("{: <1$}", "", column);
+    println!("^ ERROR: {message}");
+}
+
+/// Whether `source` needs more lines, because it has unclosed parentheses or block comments,
+/// or ends in an operator.
+pub fn is_incomplete(source: &str) -> bool {
+    let source = format!("{source}\n");
+
+    let mut interner = Interner::new();
+    let mut view = StrView::new(&source);
+
+    let Ok(tokens) = parse_raw_tokens(&mut view, &mut interner) else {
+        return false;
+    };
+
+    let mut depth = 0isize;
+    let mut last = None;
+
+    for token in tokens {
+        match token {
+            Ok(token) => {
+                match token.inner {
+                    RawToken::Symbol(Symbol::LeftParen) => depth += 1,
+                    RawToken::Symbol(Symbol::RightParen) => depth -= 1,
+                    _ => {}
+                }
+
+                last = Some(token.inner);
+            }
+            // `/` is always a valid symbol, so only an unterminated comment errors on a `/*`
+            Err(err) => {
+                let start = err.spanned().span.start;
+
+                return source.chars().skip(start).take(2).eq("/*".chars());
+            }
+        }
+    }
+
+    depth > 0
+        || matches!(
+            last,
+            Some(RawToken::Symbol(symbol))
+                if !matches!(symbol, Symbol::RightParen | Symbol::Semicolon)
+        )
+}
+
+pub fn console() {
+    let mut session = Session::new();
+
+    let mut editor = match Editor::<CalcHelper, DefaultHistory>::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            println!("!> Could not start the line editor: {err}");
+            return;
+        }
+    };
+
+    let mut helper = CalcHelper::default();
+    helper.refresh(&session.engine.state, &session.engine.interner);
+    editor.set_helper(Some(helper));
+
+    let history = history_path();
+
+    if let Some(path) = &history {
+        // there is no history yet on the first run
+        let _ = editor.load_history(path);
+    }
+
+    'session: loop {
+        let mut line = String::new();
+        let mut prompt = "< ";
+
+        loop {
+            match editor.readline(prompt) {
+                Ok(next) => line.push_str(&next),
+                // Ctrl-C cancels the line being typed
+                Err(ReadlineError::Interrupted) => continue 'session,
+                // end of input, e.g. Ctrl-D
+                Err(ReadlineError::Eof) => break 'session,
+                Err(ReadlineError::Io(err)) if err.kind() == ErrorKind::InvalidData => {
+                    println!("!> Input was not valid UTF-8\n");
+                    continue 'session;
+                }
+                Err(err) => {
+                    println!("!> Could not read input: {err}");
+                    break 'session;
+                }
+            }
+
+            if line.starts_with(':') || !is_incomplete(&line) {
+                break;
+            }
+
+            line.push('\n');
+            prompt = ".. ";
+        }
+
+        if !line.trim().is_empty() {
+            let _ = editor.add_history_entry(line.as_str());
+        }
+
+        if line.starts_with(':') {
+            match command(&mut session, &line) {
+                Ok(Flow::Continue) => {}
+                Ok(Flow::Quit) => break 'session,
+                Err(err) => report(&line, err.span.start, err.span.end, err.inner),
+            }
+        } else {
+            session.eval(&line);
         }
 
         println!();
+
+        if let Some(helper) = editor.helper_mut() {
+            helper.refresh(&session.engine.state, &session.engine.interner);
+        }
+    }
+
+    // every way out of the loop ends here, including `:q` and a failed read
+    if let Some(path) = &history {
+        save_history(&mut editor, path);
+    }
+}
+
+fn save_history(editor: &mut Editor<CalcHelper, DefaultHistory>, path: &Path) {
+    let saved = match path.parent() {
+        Some(dir) => fs::create_dir_all(dir).map_err(ReadlineError::from),
+        None => Ok(()),
+    }
+    .and_then(|()| editor.save_history(path));
+
+    if let Err(err) = saved {
+        println!("!> Could not save history: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incomplete_input() {
+        for source in [
+            "(1 + 2",
+            "f(x, ",
+            "a = 1 +",
+            "%",
+            "1 /* a\n/* b */",
+            "x = 2 -\n3 *",
+        ] {
+            assert!(is_incomplete(source), "{source:?} should be incomplete");
+        }
+
+        for source in [
+            "",
+            "(1 + 2)",
+            "a = 1;",
+            "1 /* a */",
+            "1 // (",
+            "2 * )",
+            "1.2.3",
+        ] {
+            assert!(!is_incomplete(source), "{source:?} should be complete");
+        }
     }
 }
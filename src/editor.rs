@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+
+use calc_lang::{raw_token::is_ident_continue, run::State};
+use parsr::interner::Interner;
+use rustyline::{
+    Context, Helper, Result, completion::Completer, highlight::Highlighter, hint::Hinter,
+    validate::Validator,
+};
+
+use crate::commands::COMMANDS;
+
+/// Completes variable, function and meta-command names in the console.
+#[derive(Debug, Clone, Default)]
+pub struct CalcHelper {
+    names: Vec<String>,
+}
+
+impl CalcHelper {
+    /// Refreshes the names offered for completion from the variables and functions in `state`.
+    pub fn refresh(&mut self, state: &State, interner: &Interner) {
+        self.names = state
+            .variables
+            .keys()
+            .chain(state.functions.keys())
+            .map(|&id| interner.get(id).to_owned())
+            .collect();
+
+        self.names.sort();
+        self.names.dedup();
+    }
+
+    /// Finds the start of the word before `pos` and the names it could complete to.
+    pub fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| is_ident_continue(c))
+            .last()
+            .map_or(pos, |(i, _)| i);
+
+        let prefix = &line[start..pos];
+
+        let names = match line[..start].trim_start() == ":" {
+            true => COMMANDS
+                .iter()
+                .map(|(command, ..)| command.to_string())
+                .filter(|command| command.starts_with(prefix))
+                .collect(),
+            false => self
+                .names
+                .iter()
+                .filter(|name| name.starts_with(prefix))
+                .cloned()
+                .collect(),
+        };
+
+        (start, names)
+    }
+}
+
+impl Completer for CalcHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Hinter for CalcHelper {
+    type Hint = String;
+}
+
+impl Highlighter for CalcHelper {}
+
+impl Validator for CalcHelper {}
+
+impl Helper for CalcHelper {}
+
+/// Where console history is kept between sessions, inside the user's data directory.
+pub fn history_path() -> Option<PathBuf> {
+    Some(dirs::data_dir()?.join("calc_lang").join("history"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completion() {
+        let mut interner = Interner::new();
+        let mut state = State::with_builtins(&mut interner);
+
+        state.variables.insert(interner.insert("total"), 1.0);
+        state.variables.insert(interner.insert("tax_rate"), 0.2);
+
+        let mut helper = CalcHelper::default();
+        helper.refresh(&state, &interner);
+
+        assert_eq!(
+            helper.candidates("x = ta", 6),
+            (4, vec!["tan".to_owned(), "tax_rate".to_owned()])
+        );
+        assert_eq!(
+            helper.candidates("1 + to", 6),
+            (4, vec!["total".to_owned()])
+        );
+        assert_eq!(helper.candidates(":ra", 3), (1, vec!["radix".to_owned()]));
+        assert_eq!(helper.candidates("ro", 2), (0, vec!["round".to_owned()]));
+    }
+}
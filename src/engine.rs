@@ -0,0 +1,507 @@
+use std::{borrow::Cow, fmt::Display, iter};
+
+use gxhash::HashMap;
+use parsr::{
+    input::StrView,
+    interner::{Id, Interner},
+    parse::ParseIterError,
+    token::span::Spanned,
+};
+
+use crate::{
+    ast::build,
+    bytecode::{Chunk, Vm, compile},
+    functions::{Arity, BUILTINS},
+    optimize::optimize,
+    raw_token::{LexError, RawToken, parse_raw_tokens},
+    run::{RunErrorContainer, State, run},
+    tokens::{Token, TokenError, resolved_tokens},
+};
+
+/// A 1-based line and column in a source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    /// Finds the line and column of the char at `offset` in `source`.
+    pub fn of(source: &str, offset: usize) -> Self {
+        let mut location = Location { line: 1, column: 1 };
+
+        for c in source.chars().take(offset) {
+            if c == '\n' {
+                location.line += 1;
+                location.column = 1;
+            } else {
+                location.column += 1;
+            }
+        }
+
+        location
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// An error from lexing, resolving or running a source, with the chars it covers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+    pub message: String,
+    pub start: usize,
+    pub end: usize,
+    pub location: Location,
+}
+
+impl Diagnostic {
+    pub fn new(source: &str, start: usize, end: usize, message: impl Display) -> Self {
+        Self {
+            message: message.to_string(),
+            start,
+            end,
+            location: Location::of(source, start),
+        }
+    }
+
+    fn invalid_utf8() -> Self {
+        Self {
+            message: "Invalid UTF-8".to_owned(),
+            start: 0,
+            end: 0,
+            location: Location { line: 1, column: 1 },
+        }
+    }
+
+    fn from_run(source: &str, err: Spanned<RunErrorContainer>) -> Self {
+        Self::new(source, err.span.start, err.span.end, err.inner)
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// What a source evaluates to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    /// The result of the last statement that had one
+    Number(f64),
+    /// No statement had a result, e.g. the source only assigned variables or defined functions
+    Empty,
+}
+
+impl Value {
+    pub fn number(self) -> Option<f64> {
+        match self {
+            Value::Number(num) => Some(num),
+            Value::Empty => None,
+        }
+    }
+}
+
+impl From<Option<f64>> for Value {
+    fn from(value: Option<f64>) -> Self {
+        value.map_or(Value::Empty, Value::Number)
+    }
+}
+
+/// A source that has been lexed and resolved, so it can be run any number of times.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    source: String,
+    tokens: Vec<Spanned<Token>>,
+}
+
+/// A source optimized and lowered to bytecode, for running with `Engine::execute`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bytecode {
+    source: String,
+    chunk: Chunk,
+}
+
+impl Bytecode {
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+}
+
+/// Owns everything needed to run calc_lang sources, for embedding it in other programs.
+pub struct Engine {
+    pub interner: Interner,
+    pub state: State,
+    /// The most errors `eval` and `compile` report
+    pub max_diagnostics: usize,
+    /// The id of every name interned through the engine, so finding one by name doesn't intern it
+    names: HashMap<String, Id>,
+}
+
+type RawTokenResult = Result<Spanned<RawToken>, ParseIterError<Spanned<LexError>>>;
+
+/// The diagnostics of the errors among resolved tokens.
+fn token_errors<'a>(
+    source: &'a str,
+    tokens: impl Iterator<Item = Result<Spanned<Token>, Spanned<TokenError>>> + 'a,
+) -> impl Iterator<Item = Diagnostic> + 'a {
+    tokens
+        .filter_map(Result::err)
+        .map(|err| Diagnostic::new(source, err.span.start, err.span.end, err.inner))
+}
+
+/// The lexer needs a character after the last token to know it has ended.
+pub(crate) fn terminated(source: &str) -> Cow<'_, str> {
+    match source.ends_with('\n') {
+        true => Cow::Borrowed(source),
+        false => Cow::Owned(format!("{source}\n")),
+    }
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        let mut interner = Interner::new();
+        let state = State::with_builtins(&mut interner);
+
+        let names = BUILTINS
+            .iter()
+            .map(|&(name, _)| (name.to_owned(), interner.insert(name)))
+            .collect();
+
+        Self {
+            interner,
+            state,
+            max_diagnostics: 20,
+            names,
+        }
+    }
+
+    /// Interns a name, remembering it for `id_of`.
+    pub fn intern(&mut self, name: &str) -> Id {
+        let id = self.interner.insert(name);
+
+        if !self.names.contains_key(name) {
+            self.names.insert(name.to_owned(), id);
+        }
+
+        id
+    }
+
+    /// Lexes a source, remembering the name of every identifier in it.
+    fn lex(&mut self, source: &str) -> Result<Vec<RawTokenResult>, Vec<Diagnostic>> {
+        let mut view = StrView::new(source);
+
+        let raw_tokens = parse_raw_tokens(&mut view, &mut self.interner)
+            .map_err(|_| vec![Diagnostic::invalid_utf8()])?
+            .collect::<Vec<_>>();
+
+        for token in raw_tokens.iter().flatten() {
+            if let RawToken::Ident(id) = token.inner {
+                let name = self.interner.get(id);
+
+                if !self.names.contains_key(name) {
+                    self.names.insert(name.to_owned(), id);
+                }
+            }
+        }
+
+        Ok(raw_tokens)
+    }
+
+    /// Runs a source, returning the result of the last statement that had one.
+    ///
+    /// Running stops at the first error, but the rest of the source is still resolved, so the
+    /// error of every statement that does not resolve is returned too, up to `max_diagnostics`.
+    pub fn eval(&mut self, source: &str) -> Result<Value, Vec<Diagnostic>> {
+        let source = terminated(source);
+
+        let raw_tokens = self.lex(&source)?;
+
+        let mut tokens = resolved_tokens(raw_tokens.into_iter());
+
+        match run(&mut self.state, tokens.by_ref()) {
+            Ok(result) => Ok(Value::from(result)),
+            Err(err) => Err(iter::once(Diagnostic::from_run(&source, err))
+                .chain(token_errors(&source, tokens))
+                .take(self.max_diagnostics)
+                .collect()),
+        }
+    }
+
+    /// Lexes and resolves a source without running it, returning the error of every statement
+    /// that does not resolve, up to `max_diagnostics`.
+    pub fn compile(&mut self, source: &str) -> Result<Program, Vec<Diagnostic>> {
+        let source = terminated(source).into_owned();
+
+        let raw_tokens = self.lex(&source)?;
+
+        let (tokens, errors) =
+            resolved_tokens(raw_tokens.into_iter()).partition::<Vec<_>, _>(Result::is_ok);
+
+        if !errors.is_empty() {
+            return Err(token_errors(&source, errors.into_iter())
+                .take(self.max_diagnostics)
+                .collect());
+        }
+
+        let tokens = tokens.into_iter().flatten().collect();
+
+        Ok(Program { source, tokens })
+    }
+
+    /// Lexes and resolves a source, then optimizes and lowers it to bytecode.
+    pub fn compile_bytecode(&mut self, source: &str) -> Result<Bytecode, Vec<Diagnostic>> {
+        let program = self.compile(source)?;
+
+        let tree = build(program.tokens.iter().copied().map(Ok)).map_err(|err| {
+            vec![Diagnostic::new(
+                &program.source,
+                err.span.start,
+                err.span.end,
+                err.inner,
+            )]
+        })?;
+
+        Ok(Bytecode {
+            source: program.source,
+            chunk: compile(&optimize(tree, self.state.numeric)),
+        })
+    }
+
+    /// Runs a compiled program, returning the result of the last statement that had one. It has
+    /// already resolved, so only a single runtime error can stop it.
+    pub fn run(&mut self, program: &Program) -> Result<Value, Diagnostic> {
+        run(&mut self.state, program.tokens.iter().copied().map(Ok))
+            .map(Value::from)
+            .map_err(|err| Diagnostic::from_run(&program.source, err))
+    }
+
+    /// Runs bytecode on a `Vm`, whose slots hold its variables instead of the state. This skips
+    /// looking variables up by name, for sources that are run many times.
+    ///
+    /// Slots that are unassigned, or whose variable has changed since the last execute, are
+    /// first loaded from the engine's variables, so globals set with `set_var` are seen. Other
+    /// slots keep their value, and assignments stay in the slots until `Vm::store` writes them
+    /// back.
+    pub fn execute(&mut self, bytecode: &Bytecode, vm: &mut Vm) -> Result<Value, Diagnostic> {
+        vm.load_changed(&bytecode.chunk, &self.state);
+
+        vm.run(&bytecode.chunk, &mut self.state)
+            .map(Value::from)
+            .map_err(|err| Diagnostic::from_run(&bytecode.source, err))
+    }
+
+    /// Finds the `Vm` slot of a variable used by bytecode.
+    pub fn slot(&self, bytecode: &Bytecode, name: &str) -> Option<usize> {
+        bytecode.chunk.slot(self.id_of(name)?)
+    }
+
+    /// Registers a function implemented by the host program, callable as `name(args)`.
+    pub fn register(
+        &mut self,
+        name: &str,
+        arity: Arity,
+        call: impl Fn(&[f64]) -> Result<f64, String> + 'static,
+    ) {
+        self.intern(name);
+        self.state.register(&mut self.interner, name, arity, call);
+    }
+
+    /// Finds the id of a name without interning it, so looking up names that were never used
+    /// does not grow the interner. Names interned directly through `interner` are not found.
+    pub fn id_of(&self, name: &str) -> Option<Id> {
+        self.names.get(name).copied()
+    }
+
+    pub fn set_var(&mut self, name: &str, value: f64) {
+        let id = self.intern(name);
+
+        self.state.variables.insert(id, value);
+    }
+
+    pub fn get_var(&self, name: &str) -> Option<f64> {
+        let id = self.id_of(name)?;
+
+        self.state.variables.get(&id).copied()
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations() {
+        let source = "a = 1;\nb = 2;\n\nc";
+
+        assert_eq!(Location::of(source, 0), Location { line: 1, column: 1 });
+        assert_eq!(Location::of(source, 4), Location { line: 1, column: 5 });
+        assert_eq!(Location::of(source, 7), Location { line: 2, column: 1 });
+        assert_eq!(Location::of(source, 15), Location { line: 4, column: 1 });
+    }
+
+    #[test]
+    fn eval() {
+        let mut engine = Engine::new();
+
+        assert_eq!(
+            engine.eval("a = 2; b = sqrt(a * 8)"),
+            Ok(Value::Number(4.0))
+        );
+        assert_eq!(engine.eval("f(x) = x * b"), Ok(Value::Empty));
+        assert_eq!(engine.get_var("b"), Some(4.0));
+
+        engine.set_var("c", 0.5);
+        assert_eq!(engine.eval("f(c) + a;"), Ok(Value::Number(4.0)));
+
+        // an engine keeps no history, however many sources it runs
+        for _ in 0..3 {
+            assert_eq!(engine.eval("a * 2; b"), Ok(Value::Number(4.0)));
+        }
+
+        assert_eq!(engine.state.history, vec![]);
+
+        let err = &engine.eval("# totals\nc = a + b;\nd = c + e").unwrap_err()[0];
+
+        assert_eq!(err.message, "Unassigned variable");
+        assert_eq!(err.location, Location { line: 3, column: 9 });
+        assert_eq!(err.to_string(), "3:9: Unassigned variable");
+    }
+
+    #[test]
+    fn compile() {
+        let mut engine = Engine::new();
+
+        let program = engine.compile("x * 2 + 1").unwrap();
+
+        for x in [1.0, 2.0, 3.5] {
+            engine.set_var("x", x);
+
+            assert_eq!(engine.run(&program), Ok(Value::Number(x * 2.0 + 1.0)));
+        }
+
+        let bytecode = engine.compile_bytecode("x * 2 + 1").unwrap();
+
+        let mut vm = Vm::new(bytecode.chunk());
+        let x = engine.slot(&bytecode, "x").unwrap();
+        assert_eq!(engine.slot(&bytecode, "unused"), None);
+        assert_eq!(engine.id_of("unused"), None);
+
+        for x_value in [1.0, 2.0, 3.5] {
+            vm.set(x, x_value);
+
+            assert_eq!(
+                engine.execute(&bytecode, &mut vm),
+                Ok(Value::Number(x_value * 2.0 + 1.0))
+            );
+        }
+
+        assert_eq!(engine.state.history, vec![]);
+
+        // slots are loaded from the globals that changed, assignments stay in the slots
+        let bytecode = engine.compile_bytecode("y = x + offset; y").unwrap();
+
+        let mut vm = Vm::new(bytecode.chunk());
+
+        engine.set_var("offset", 10.0);
+        assert_eq!(engine.execute(&bytecode, &mut vm), Ok(Value::Number(13.5)));
+
+        engine.set_var("x", 0.0);
+        assert_eq!(engine.execute(&bytecode, &mut vm), Ok(Value::Number(10.0)));
+        assert_eq!(engine.get_var("y"), None);
+
+        let offset = engine.slot(&bytecode, "offset").unwrap();
+
+        vm.set(offset, 1.0);
+        assert_eq!(engine.execute(&bytecode, &mut vm), Ok(Value::Number(1.0)));
+
+        let err = &engine.compile("1 + (2").unwrap_err()[0];
+        assert_eq!(err.location, Location { line: 1, column: 5 });
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut engine = Engine::new();
+
+        let locations = |diagnostics: Vec<Diagnostic>| {
+            diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.location)
+                .collect::<Vec<_>>()
+        };
+
+        let diagnostics = engine
+            .eval("a = 1 +* 2;\nb = (3 4;\nc = 5;\nd = 6 7")
+            .unwrap_err();
+
+        assert_eq!(
+            locations(diagnostics),
+            [
+                Location { line: 1, column: 8 },
+                Location { line: 2, column: 8 },
+                Location { line: 4, column: 7 },
+            ]
+        );
+
+        // a statement is cut at the first `;` after its error, even inside brackets
+        let diagnostics = engine.eval("b = (3;\nc = 5").unwrap_err();
+
+        assert_eq!(locations(diagnostics), [Location { line: 1, column: 5 }]);
+        assert_eq!(engine.get_var("c"), None);
+
+        // running stops at the first error, but later statements are still resolved
+        let diagnostics = engine.eval("x = y;\n1 +* 2").unwrap_err();
+
+        assert_eq!(diagnostics[0].message, "Unassigned variable");
+        assert_eq!(
+            locations(diagnostics),
+            [
+                Location { line: 1, column: 5 },
+                Location { line: 2, column: 4 },
+            ]
+        );
+
+        assert_eq!(engine.get_var("a"), None);
+
+        // a statement can't end inside brackets
+        let diagnostics = engine.eval("(1; 2) + 3").unwrap_err();
+
+        assert_eq!(
+            diagnostics[0].message,
+            "Cannot end a statement inside brackets"
+        );
+        assert_eq!(locations(diagnostics), [Location { line: 1, column: 3 }]);
+
+        engine.max_diagnostics = 2;
+        assert_eq!(engine.eval("1 +* 2; 3 4; 5 6;").unwrap_err().len(), 2);
+        assert_eq!(engine.compile("1 +* 2; 3 4; 5 6;").unwrap_err().len(), 2);
+    }
+
+    #[test]
+    fn register() {
+        let mut engine = Engine::new();
+
+        engine.register("tax", Arity::Exact(1), |a| match a[0] < 0.0 {
+            true => Err("Negative amount".to_owned()),
+            false => Ok(a[0] * 0.2),
+        });
+
+        assert_eq!(engine.eval("tax(100)"), Ok(Value::Number(20.0)));
+
+        let err = &engine.eval("x = 2 *\n  tax(-1)").unwrap_err()[0];
+        assert_eq!(err.message, "Negative amount");
+        assert_eq!(err.location, Location { line: 2, column: 3 });
+    }
+}
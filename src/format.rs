@@ -0,0 +1,529 @@
+use parsr::{
+    input::StrView,
+    interner::Interner,
+    token::{
+        span::{Span, Spanned},
+        token::{Associativity, IsResolvedToken, TokenType},
+    },
+};
+
+use crate::{
+    Diagnostic,
+    ast::{BinaryOp, Expr, UnaryOp, build},
+    engine::terminated,
+    raw_token::{RawToken, Symbol, parse_raw_tokens_with_comments},
+    tokens::{Operator, resolved_tokens},
+};
+
+/// Re-emits a source with canonical spacing, the fewest parentheses that keep its meaning and
+/// one statement per line.
+///
+/// Comments are kept: those after a statement on its line stay there, and the rest go on their
+/// own line before the statement they were in or before. Runs of blank lines become one.
+pub fn format(source: &str) -> Result<String, Diagnostic> {
+    let source = terminated(source);
+
+    let mut interner = Interner::new();
+    let mut view = StrView::new(&source);
+
+    let mut comments = Vec::new();
+
+    let tokens = parse_raw_tokens_with_comments(&mut view, &mut interner, &mut comments)
+        .map_err(|_| Diagnostic::new(&source, 0, 0, "Invalid UTF-8"))?
+        .collect::<Vec<_>>();
+
+    let tree = build(resolved_tokens(tokens.iter().copied()))
+        .map_err(|err| Diagnostic::new(&source, err.span.start, err.span.end, err.inner))?;
+
+    let Expr::Seq(statements) = tree.inner else {
+        unreachable!("`build` returns the statements as a sequence");
+    };
+
+    // the tree built without errors, so neither did lexing
+    let mut items = tokens
+        .into_iter()
+        .flatten()
+        .map(Item::Token)
+        .chain(comments.into_iter().map(Item::Comment))
+        .collect::<Vec<_>>();
+
+    items.sort_by_key(|item| item.span().start);
+
+    let mut formatter = Formatter {
+        source: source.chars().collect(),
+        interner: &interner,
+        lines: Vec::new(),
+        pending: Vec::new(),
+        end: None,
+    };
+
+    let mut statements = statements.iter();
+
+    let semicolon = |item: &Item| matches!(item, Item::Token(token) if token.inner == RawToken::Symbol(Symbol::Semicolon));
+
+    // statements end at the `;` outside of brackets, like in `tokens::statement`
+    let mut depth = 0usize;
+
+    let ends_statement = |item: &Item| {
+        let Item::Token(token) = item else {
+            return false;
+        };
+
+        match token.inner {
+            RawToken::Symbol(Symbol::LeftParen) => depth += 1,
+            RawToken::Symbol(Symbol::RightParen) => depth = depth.saturating_sub(1),
+            RawToken::Symbol(Symbol::Semicolon) => return depth == 0,
+            _ => (),
+        }
+
+        false
+    };
+
+    let mismatch = |span: Span| {
+        Diagnostic::new(
+            &source,
+            span.start,
+            span.end,
+            "Statement does not match the tree built from the source",
+        )
+    };
+
+    for segment in items.split_inclusive(ends_statement) {
+        let terminated = segment.last().is_some_and(semicolon);
+
+        let code = match terminated {
+            true => &segment[..segment.len() - 1],
+            false => segment,
+        };
+
+        let first = code.iter().position(Item::is_token);
+        let last = code.iter().rposition(Item::is_token);
+
+        let (Some(first), Some(last)) = (first, last) else {
+            // an empty statement, which only leaves its comments
+            for comment in code {
+                formatter.comment(comment.span());
+            }
+
+            continue;
+        };
+
+        for comment in &code[..first] {
+            formatter.comment(comment.span());
+        }
+
+        // comments inside a statement go before it
+        for item in &code[first..last] {
+            if let Item::Comment(span) = item {
+                formatter.pending.push(*span);
+            }
+        }
+
+        let statement = statements
+            .next()
+            .ok_or_else(|| mismatch(code[first].span()))?;
+
+        let mut line = formatter.expr(statement, None, false);
+
+        let mut end = code[last].span();
+
+        if terminated {
+            line.push(';');
+            end = segment[segment.len() - 1].span();
+        }
+
+        formatter.line(code[first].span(), line, end);
+
+        for comment in &code[last + 1..] {
+            formatter.comment(comment.span());
+        }
+    }
+
+    if let Some(statement) = statements.next() {
+        return Err(mismatch(statement.span));
+    }
+
+    formatter.flush();
+
+    let mut formatted = formatter.lines.join("\n");
+    formatted.push('\n');
+
+    Ok(formatted)
+}
+
+/// Formats a tree on one line, with numbers printed by value. This is for trees that no longer
+/// match their source, like those of `optimize`, whose folded numbers span what they replaced.
+pub fn format_tree(tree: &Spanned<Expr>, interner: &Interner) -> String {
+    let formatter = Formatter {
+        source: Vec::new(),
+        interner,
+        lines: Vec::new(),
+        pending: Vec::new(),
+        end: None,
+    };
+
+    formatter.expr(tree, None, false)
+}
+
+/// A token or a comment, in the order they are in the source.
+enum Item {
+    Token(Spanned<RawToken>),
+    Comment(Span),
+}
+
+impl Item {
+    fn span(&self) -> Span {
+        match self {
+            Item::Token(token) => token.span,
+            Item::Comment(span) => *span,
+        }
+    }
+
+    fn is_token(&self) -> bool {
+        matches!(self, Item::Token(_))
+    }
+}
+
+struct Formatter<'a> {
+    /// The source the tree was built from, empty when numbers are printed by value
+    source: Vec<char>,
+    interner: &'a Interner,
+    lines: Vec<String>,
+    /// Comments waiting to go on their own line before the next statement
+    pending: Vec<Span>,
+    /// Where the source of the last line ends, while a comment can still follow it
+    end: Option<Span>,
+}
+
+impl Formatter<'_> {
+    fn text(&self, span: Span) -> String {
+        self.source[span.start..span.end].iter().collect()
+    }
+
+    fn count(&self, start: usize, end: usize, c: char) -> usize {
+        self.source[start.min(end)..end]
+            .iter()
+            .filter(|&&found| found == c)
+            .count()
+    }
+
+    /// Adds a comment after the last line if it was on the same line, or before the next.
+    fn comment(&mut self, span: Span) {
+        match self.end {
+            Some(end) if self.count(end.end, span.start, '\n') == 0 => {
+                let text = self.text(span);
+
+                self.lines.last_mut().unwrap().push(' ');
+                self.lines.last_mut().unwrap().push_str(&text);
+
+                self.end = Some(span);
+            }
+            _ => self.pending.push(span),
+        }
+    }
+
+    /// Adds a line of output starting where `start` does in the source and ending at `end`,
+    /// after the pending comments.
+    fn line(&mut self, start: Span, line: String, end: Span) {
+        self.flush();
+
+        self.blank_line(start);
+        self.lines.push(line);
+
+        self.end = Some(end);
+    }
+
+    fn flush(&mut self) {
+        for span in std::mem::take(&mut self.pending) {
+            self.blank_line(span);
+
+            let text = self.text(span);
+
+            self.lines.push(text);
+
+            self.end = Some(span);
+        }
+    }
+
+    /// Keeps one blank line where the source had any before `start`.
+    fn blank_line(&mut self, start: Span) {
+        if let Some(end) = self.end
+            && self.count(end.end, start.start, '\n') > 1
+        {
+            self.lines.push(String::new());
+        }
+    }
+
+    /// Formats an expression, parenthesised if it would otherwise bind differently as an operand
+    /// of `parent`, on its right side if `right` is set.
+    fn expr(&self, node: &Spanned<Expr>, parent: Option<Operator>, right: bool) -> String {
+        let formatted = match &node.inner {
+            // keeps how the number was written, e.g. `1e3` or `0.50`
+            Expr::Number(num) => match self.source.is_empty() {
+                true => num.to_string(),
+                false => self.text(node.span),
+            },
+            Expr::Ident(id) => self.interner.get(*id).to_owned(),
+            Expr::History(index) => format!("_{index}"),
+            Expr::Unary { op, operand } => {
+                let (symbol, operator) = match op {
+                    UnaryOp::Neg => ("-", Operator::Neg),
+                    UnaryOp::Pos => ("+", Operator::Pos),
+                };
+
+                format!("{symbol}{}", self.expr(operand, Some(operator), true))
+            }
+            Expr::Binary { op, left, right } => {
+                let symbol = match op {
+                    BinaryOp::Add => '+',
+                    BinaryOp::Sub => '-',
+                    BinaryOp::Mul => '*',
+                    BinaryOp::Div => '/',
+                    BinaryOp::Pow => '^',
+                };
+
+                let operator = Some(op.operator());
+
+                format!(
+                    "{} {symbol} {}",
+                    self.expr(left, operator, false),
+                    self.expr(right, operator, true)
+                )
+            }
+            Expr::Assign { target, value } => {
+                // `f(x) = 1` would define `f`, and `_1 = 1` is rejected before it runs
+                let target = match target.inner {
+                    Expr::Call { .. } | Expr::History(_) => {
+                        format!("({})", self.expr(target, None, false))
+                    }
+                    _ => self.expr(target, Some(Operator::Equals), false),
+                };
+
+                format!(
+                    "{target} = {}",
+                    self.expr(value, Some(Operator::Equals), true)
+                )
+            }
+            Expr::Print(operand) => {
+                format!("% {}", self.expr(operand, Some(Operator::Print), true))
+            }
+            Expr::Call {
+                function,
+                arguments,
+            } => format!(
+                "{}({})",
+                self.interner.get(*function),
+                arguments
+                    .iter()
+                    .map(|argument| self.expr(argument, Some(Operator::Comma), true))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Expr::Define { name, params, body } => format!(
+                "{}({}) = {}",
+                self.interner.get(*name),
+                params
+                    .iter()
+                    .map(|param| self.interner.get(param.inner))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                self.expr(body, None, true)
+            ),
+            Expr::Seq(statements) => statements
+                .iter()
+                .map(|statement| self.expr(statement, None, false))
+                .collect::<Vec<_>>()
+                .join("; "),
+        };
+
+        match parent.is_some_and(|parent| needs_parens(parent, &node.inner, right)) {
+            true => format!("({formatted})"),
+            false => formatted,
+        }
+    }
+}
+
+/// The operator a node is built around, which decides how tightly it binds.
+fn operator(node: &Expr) -> Option<Operator> {
+    match node {
+        // only folding makes negative numbers, which print with a `-`
+        Expr::Number(num) if num.is_sign_negative() => Some(Operator::Neg),
+        Expr::Unary {
+            op: UnaryOp::Neg, ..
+        } => Some(Operator::Neg),
+        Expr::Unary {
+            op: UnaryOp::Pos, ..
+        } => Some(Operator::Pos),
+        Expr::Binary { op, .. } => Some(op.operator()),
+        Expr::Assign { .. } => Some(Operator::Equals),
+        Expr::Print(_) => Some(Operator::Print),
+        Expr::Seq(_) => Some(Operator::Semicolon),
+        Expr::Number(_)
+        | Expr::Ident(_)
+        | Expr::History(_)
+        | Expr::Call { .. }
+        | Expr::Define { .. } => None,
+    }
+}
+
+/// Whether `child` needs parentheses to be an operand of `parent`, using the precedence and
+/// associativity the resolver does.
+fn needs_parens(parent: Operator, child: &Expr, right: bool) -> bool {
+    let Some(child) = operator(child) else {
+        return false;
+    };
+
+    let (
+        TokenType::Precedence {
+            precedence: parent_precedence,
+            associativity,
+        },
+        TokenType::Precedence {
+            precedence: child_precedence,
+            ..
+        },
+    ) = (parent.get_type(), child.get_type())
+    else {
+        unreachable!("operators always have a precedence");
+    };
+
+    child_precedence < parent_precedence
+        || (child_precedence == parent_precedence
+            && (associativity == Associativity::Left) == right)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raw_token::parse_raw_tokens;
+
+    use super::*;
+
+    /// The tree of a source with every span cleared, so trees of differently spaced sources
+    /// compare equal.
+    fn shape(source: &str) -> (Expr, Vec<String>) {
+        fn clear(node: &mut Spanned<Expr>) {
+            node.span = Span::default();
+
+            match &mut node.inner {
+                Expr::Number(_) | Expr::Ident(_) | Expr::History(_) => {}
+                Expr::Unary { operand, .. } | Expr::Print(operand) => clear(operand),
+                Expr::Binary { left, right, .. } => {
+                    clear(left);
+                    clear(right);
+                }
+                Expr::Assign { target, value } => {
+                    clear(target);
+                    clear(value);
+                }
+                Expr::Call { arguments, .. } => arguments.iter_mut().for_each(clear),
+                Expr::Define { params, body, .. } => {
+                    params
+                        .iter_mut()
+                        .for_each(|param| param.span = Span::default());
+                    clear(body);
+                }
+                Expr::Seq(statements) => statements.iter_mut().for_each(clear),
+            }
+        }
+
+        let mut interner = Interner::new();
+        let mut view = StrView::new(source);
+
+        let mut tree = build(resolved_tokens(
+            parse_raw_tokens(&mut view, &mut interner).unwrap(),
+        ))
+        .unwrap();
+
+        clear(&mut tree);
+
+        let mut view = StrView::new(source);
+        let mut comments = Vec::new();
+
+        parse_raw_tokens_with_comments(&mut view, &mut interner, &mut comments)
+            .unwrap()
+            .for_each(drop);
+
+        let comments = comments
+            .into_iter()
+            .map(|span| {
+                source
+                    .chars()
+                    .skip(span.start)
+                    .take(span.end - span.start)
+                    .collect()
+            })
+            .collect();
+
+        (tree.inner, comments)
+    }
+
+    #[test]
+    fn formatting() {
+        for (source, expected) in [
+            ("a=1+2*3", "a = 1 + 2 * 3\n"),
+            ("(a)=((1+2))*3;", "a = (1 + 2) * 3;\n"),
+            ("1-(2-3)-(4-5)", "1 - (2 - 3) - (4 - 5)\n"),
+            ("(2^3)^4^(5^6)", "(2 ^ 3) ^ 4 ^ 5 ^ 6\n"),
+            ("-(2^2)+(-2)^2+2^(-2)", "-2 ^ 2 + (-2) ^ 2 + 2 ^ -2\n"),
+            ("(%a)*2; %(a*2)", "% a * 2;\n% (a * 2)\n"),
+            (
+                "f( x,y )=x*(y+1);f(1,(2))^2",
+                "f(x, y) = x * (y + 1);\nf(1, 2) ^ 2\n",
+            ),
+            ("a=(b=2)*(c=_1)", "a = (b = 2) * (c = _1)\n"),
+            ("0x1F+1_000.5e-1+g()", "0x1F + 1_000.5e-1 + g()\n"),
+        ] {
+            assert_eq!(format(source).unwrap(), expected, "{source:?}");
+        }
+    }
+
+    #[test]
+    fn comments() {
+        let source = "# setup\na=1;  // one\n\n\n/* two */ b = 2 +\n  // inner\n  3; c\n// end\n";
+
+        assert_eq!(
+            format(source).unwrap(),
+            "# setup\na = 1; // one\n\n/* two */\n// inner\nb = 2 + 3;\nc\n// end\n"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for source in [
+            "a = -2 ^ -(3 - 1) * +4 / (5 / 6); % a - -a",
+            "f(x) = max(x, 1, 2) ^ 2 # square\n; f(3) /* call */ + _1;;",
+            "x = y = (z = 2) + 1; (1 + 2) = 3",
+            "/* a /* nested */ comment */\n\n\n1 // trailing\n// last",
+            "h(a, b) = a / (b * (a - b)) ^ (1 / 2)",
+            "- -x + +-y",
+            "(f(x)) = 1; (_1) = 2",
+        ] {
+            let formatted = format(source).unwrap();
+
+            assert_eq!(
+                shape(&format!("{source}\n")),
+                shape(&formatted),
+                "{source:?}"
+            );
+            assert_eq!(format(&formatted).unwrap(), formatted, "{source:?}");
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let err = format("a = (1 +\n2").unwrap_err();
+
+        assert_eq!(err.location.line, 1);
+
+        // statements can't end inside brackets
+        for source in ["(;1)", "(a = 1; a = 2)"] {
+            let column = source.find(';').unwrap() + 1;
+
+            assert_eq!(
+                format(source).unwrap_err().location.column,
+                column,
+                "{source:?}"
+            );
+        }
+    }
+}
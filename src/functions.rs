@@ -0,0 +1,145 @@
+use std::{
+    fmt::{Debug, Display},
+    rc::Rc,
+};
+
+use gxhash::HashMap;
+use parsr::{
+    interner::{Id, Interner},
+    token::span::Spanned,
+};
+
+use crate::tokens::Token;
+
+/// The number of arguments a function accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Arity {
+    Exact(usize),
+    Between(usize, usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(self, count: usize) -> bool {
+        match self {
+            Arity::Exact(n) => count == n,
+            Arity::Between(min, max) => (min..=max).contains(&count),
+            Arity::AtLeast(min) => count >= min,
+        }
+    }
+}
+
+impl Display for Arity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (count, last) = match self {
+            Arity::Exact(n) => (n.to_string(), *n),
+            Arity::Between(min, max) => (format!("{min} to {max}"), *max),
+            Arity::AtLeast(min) => (format!("at least {min}"), *min),
+        };
+
+        match last {
+            1 => write!(f, "{count} argument"),
+            _ => write!(f, "{count} arguments"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Builtin {
+    pub arity: Arity,
+    pub call: fn(&[f64]) -> f64,
+}
+
+impl Builtin {
+    pub const fn new(arity: Arity, call: fn(&[f64]) -> f64) -> Self {
+        Self { arity, call }
+    }
+}
+
+/// A function defined with `name(params) = body`, its body kept as resolved tokens.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserFunction {
+    pub params: Vec<Id>,
+    pub body: Vec<Spanned<Token>>,
+}
+
+/// The signature of functions registered by the program embedding calc_lang.
+pub type NativeFn = dyn Fn(&[f64]) -> Result<f64, String>;
+
+/// A function registered by the program embedding calc_lang, whose errors are reported at the
+/// call site.
+#[derive(Clone)]
+pub struct Native {
+    pub arity: Arity,
+    pub call: Rc<NativeFn>,
+}
+
+impl Native {
+    pub fn new(arity: Arity, call: impl Fn(&[f64]) -> Result<f64, String> + 'static) -> Self {
+        Self {
+            arity,
+            call: Rc::new(call),
+        }
+    }
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Native")
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Function {
+    Builtin(Builtin),
+    Native(Native),
+    User(Rc<UserFunction>),
+}
+
+pub const BUILTINS: [(&str, Builtin); 19] = [
+    ("sin", Builtin::new(Arity::Exact(1), |a| a[0].sin())),
+    ("cos", Builtin::new(Arity::Exact(1), |a| a[0].cos())),
+    ("tan", Builtin::new(Arity::Exact(1), |a| a[0].tan())),
+    ("asin", Builtin::new(Arity::Exact(1), |a| a[0].asin())),
+    ("acos", Builtin::new(Arity::Exact(1), |a| a[0].acos())),
+    ("atan", Builtin::new(Arity::Exact(1), |a| a[0].atan())),
+    ("atan2", Builtin::new(Arity::Exact(2), |a| a[0].atan2(a[1]))),
+    ("sqrt", Builtin::new(Arity::Exact(1), |a| a[0].sqrt())),
+    ("cbrt", Builtin::new(Arity::Exact(1), |a| a[0].cbrt())),
+    ("exp", Builtin::new(Arity::Exact(1), |a| a[0].exp())),
+    ("ln", Builtin::new(Arity::Exact(1), |a| a[0].ln())),
+    // `log(x)` is base 10, `log(x, b)` is base `b`
+    (
+        "log",
+        Builtin::new(Arity::Between(1, 2), |a| match a {
+            [x] => x.log10(),
+            [x, base] => x.log(*base),
+            _ => unreachable!(),
+        }),
+    ),
+    ("abs", Builtin::new(Arity::Exact(1), |a| a[0].abs())),
+    ("floor", Builtin::new(Arity::Exact(1), |a| a[0].floor())),
+    ("ceil", Builtin::new(Arity::Exact(1), |a| a[0].ceil())),
+    ("round", Builtin::new(Arity::Exact(1), |a| a[0].round())),
+    (
+        "min",
+        Builtin::new(Arity::AtLeast(1), |a| {
+            a.iter().copied().fold(f64::INFINITY, f64::min)
+        }),
+    ),
+    (
+        "max",
+        Builtin::new(Arity::AtLeast(1), |a| {
+            a.iter().copied().fold(f64::NEG_INFINITY, f64::max)
+        }),
+    ),
+    ("hypot", Builtin::new(Arity::Exact(2), |a| a[0].hypot(a[1]))),
+];
+
+pub fn register_builtins(functions: &mut HashMap<Id, Function>, interner: &mut Interner) {
+    for (name, builtin) in BUILTINS {
+        functions.insert(interner.insert(name), Function::Builtin(builtin));
+    }
+}
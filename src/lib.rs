@@ -0,0 +1,12 @@
+pub mod ast;
+pub mod bytecode;
+pub mod engine;
+pub mod format;
+pub mod functions;
+pub mod optimize;
+pub mod output;
+pub mod raw_token;
+pub mod run;
+pub mod tokens;
+
+pub use engine::{Bytecode, Diagnostic, Engine, Location, Program, Value};
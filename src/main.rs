@@ -1,10 +1,87 @@
+pub mod commands;
 pub mod console;
-pub mod raw_token;
-pub mod run;
-pub mod tokens;
+pub mod editor;
+pub mod script;
 
+use std::{
+    env, fs,
+    io::{self, IsTerminal, Read, stdin},
+    process::ExitCode,
+};
+
+use calc_lang::{Engine, format::format};
 use console::console;
+use script::script;
+
+const USAGE: &str = "usage: calc_lang [path | -e <expr> | -]
+       calc_lang fmt [path | -]
+
+a script named `fmt` is run as `calc_lang ./fmt`";
+
+pub fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+
+    let (name, source) = match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        // the subcommand takes precedence over a script named `fmt`, which `./fmt` still runs
+        ["fmt"] | ["fmt", "-"] => return fmt("<stdin>", read_stdin()),
+        ["fmt", path] if !path.starts_with('-') => return fmt(path, fs::read_to_string(path)),
+        [] if stdin().is_terminal() => {
+            console();
+            return ExitCode::SUCCESS;
+        }
+        [] | ["-"] => match read_stdin() {
+            Ok(source) => ("<stdin>".to_owned(), source),
+            Err(err) => {
+                eprintln!("<stdin>: error: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+        ["-e", expr] => ("<expr>".to_owned(), expr.to_owned()),
+        [path] if !path.starts_with('-') => match fs::read_to_string(path) {
+            Ok(source) => (path.to_owned(), source),
+            Err(err) => {
+                eprintln!("{path}: error: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match script(&mut Engine::new(), &name, &source) {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    }
+}
+
+fn read_stdin() -> io::Result<String> {
+    let mut source = String::new();
+
+    stdin().read_to_string(&mut source)?;
+
+    Ok(source)
+}
+
+/// Prints a source formatted, or the error that stopped it being formatted.
+fn fmt(name: &str, source: io::Result<String>) -> ExitCode {
+    let source = match source {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("{name}: error: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
 
-pub fn main() {
-    console();
+    match format(&source) {
+        Ok(formatted) => {
+            print!("{formatted}");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("{name}:{}: error: {}", err.location, err.message);
+            ExitCode::FAILURE
+        }
+    }
 }
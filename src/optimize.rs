@@ -0,0 +1,331 @@
+use parsr::token::span::Spanned;
+
+use crate::{
+    ast::{BinaryOp, Expr, UnaryOp},
+    run::NumericPolicy,
+};
+
+/// Folds constant sub-expressions and removes identities like `x * 1` and `x + 0`, keeping the
+/// spans of what is left so runtime errors still point at the source.
+///
+/// The result evaluates the same as the original under `numeric`, except that `x + 0` keeps the
+/// sign of a negative zero `x`. Nothing that would error under `NumericPolicy::Strict` is
+/// folded, and assignment targets are left alone. Under `Strict` identities are kept too, since
+/// `x * 1` errors when `x` is not finite but `x` alone does not.
+pub fn optimize(program: Spanned<Expr>, numeric: NumericPolicy) -> Spanned<Expr> {
+    let span = program.span;
+
+    let identities = numeric == NumericPolicy::Ieee;
+
+    match program.inner {
+        Expr::Seq(statements) => span.over(Expr::Seq(
+            statements
+                .into_iter()
+                .map(|statement| optimize_statement(statement, identities))
+                .collect(),
+        )),
+        _ => optimize_statement(program, identities),
+    }
+}
+
+fn optimize_statement(statement: Spanned<Expr>, identities: bool) -> Spanned<Expr> {
+    let span = statement.span;
+
+    match statement.inner {
+        // the root assignment resolves its value straight away, so only assignments nested in
+        // the value can change a variable before it is resolved
+        Expr::Assign { target, value } => {
+            let optimizer = Optimizer {
+                idents: !assigns(&value),
+                identities,
+            };
+
+            span.over(Expr::Assign {
+                target,
+                value: Box::new(optimizer.optimize(*value)),
+            })
+        }
+        inner => {
+            let statement = span.over(inner);
+
+            let optimizer = Optimizer {
+                idents: !assigns(&statement),
+                identities,
+            };
+
+            optimizer.optimize(statement)
+        }
+    }
+}
+
+/// Whether evaluating a node assigns a variable.
+fn assigns(node: &Spanned<Expr>) -> bool {
+    match &node.inner {
+        Expr::Number(_) | Expr::Ident(_) | Expr::History(_) | Expr::Define { .. } => false,
+        Expr::Assign { .. } => true,
+        Expr::Unary { operand, .. } | Expr::Print(operand) => assigns(operand),
+        Expr::Binary { left, right, .. } => assigns(left) || assigns(right),
+        Expr::Call { arguments, .. } => arguments.iter().any(assigns),
+        Expr::Seq(statements) => statements.iter().any(assigns),
+    }
+}
+
+struct Optimizer {
+    /// Whether an identity can be reduced to a bare identifier. An identifier is only resolved
+    /// when an operator takes it, so this would change its value if an assignment runs between.
+    idents: bool,
+    /// Whether identities like `x * 1` are removed at all
+    identities: bool,
+}
+
+impl Optimizer {
+    fn optimize(&self, node: Spanned<Expr>) -> Spanned<Expr> {
+        let span = node.span;
+
+        match node.inner {
+            Expr::Unary { op, operand } => {
+                let operand = self.optimize(*operand);
+
+                match (op, operand.inner) {
+                    (UnaryOp::Neg, Expr::Number(num)) => span.over(Expr::Number(-num)),
+                    (UnaryOp::Pos, inner) if self.is_value(&inner) => operand.span.over(inner),
+                    (op, inner) => span.over(Expr::Unary {
+                        op,
+                        operand: Box::new(operand.span.over(inner)),
+                    }),
+                }
+            }
+            Expr::Binary { op, left, right } => {
+                let left = self.optimize(*left);
+                let right = self.optimize(*right);
+
+                if let (Expr::Number(l), Expr::Number(r)) = (&left.inner, &right.inner)
+                    && let Some(num) = fold(op, *l, *r)
+                {
+                    return span.over(Expr::Number(num));
+                }
+
+                let identity = |side: &Spanned<Expr>, value| side.inner == Expr::Number(value);
+
+                // which side is left when the other is an identity for the operator
+                let keep_left = match op {
+                    _ if !self.identities => None,
+                    BinaryOp::Add if identity(&left, 0.0) => Some(false),
+                    BinaryOp::Add | BinaryOp::Sub if identity(&right, 0.0) => Some(true),
+                    BinaryOp::Mul if identity(&left, 1.0) => Some(false),
+                    BinaryOp::Mul | BinaryOp::Div | BinaryOp::Pow if identity(&right, 1.0) => {
+                        Some(true)
+                    }
+                    _ => None,
+                };
+
+                match keep_left {
+                    Some(true) if self.is_value(&left.inner) => left,
+                    Some(false) if self.is_value(&right.inner) => right,
+                    _ => span.over(Expr::Binary {
+                        op,
+                        left: Box::new(left),
+                        right: Box::new(right),
+                    }),
+                }
+            }
+            // a target is never optimized, so `x * 1 = 2` still errors
+            Expr::Assign { target, value } => span.over(Expr::Assign {
+                target,
+                value: Box::new(self.optimize(*value)),
+            }),
+            Expr::Print(operand) => span.over(Expr::Print(Box::new(self.optimize(*operand)))),
+            Expr::Call {
+                function,
+                arguments,
+            } => span.over(Expr::Call {
+                function,
+                arguments: arguments
+                    .into_iter()
+                    .map(|argument| self.optimize(argument))
+                    .collect(),
+            }),
+            Expr::Define { name, params, body } => span.over(Expr::Define {
+                name,
+                params,
+                body: Box::new(optimize_statement(*body, self.identities)),
+            }),
+            Expr::Seq(statements) => span.over(Expr::Seq(
+                statements
+                    .into_iter()
+                    .map(|statement| optimize_statement(statement, self.identities))
+                    .collect(),
+            )),
+            inner @ (Expr::Number(_) | Expr::Ident(_) | Expr::History(_)) => span.over(inner),
+        }
+    }
+
+    /// Whether a node is resolved to a number when it is evaluated, so it can stand in for an
+    /// operator that would have resolved it. Assignments and prints leave their variable.
+    fn is_value(&self, node: &Expr) -> bool {
+        match node {
+            Expr::Ident(_) => self.idents,
+            Expr::Assign { .. } | Expr::Print(_) | Expr::Define { .. } | Expr::Seq(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Applies an operator to constants, unless the result is not a finite number.
+fn fold(op: BinaryOp, left: f64, right: f64) -> Option<f64> {
+    let result = match op {
+        BinaryOp::Add => left + right,
+        BinaryOp::Sub => left - right,
+        BinaryOp::Mul => left * right,
+        BinaryOp::Div => left / right,
+        BinaryOp::Pow => left.powf(right),
+    };
+
+    result.is_finite().then_some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use parsr::{input::StrView, interner::Interner};
+
+    use crate::{
+        ast::{build, evaluate},
+        output::Buffer,
+        raw_token::parse_raw_tokens,
+        run::{NumericPolicy, State},
+        tokens::resolved_tokens,
+    };
+
+    use super::*;
+
+    fn tree(interner: &mut Interner, source: &str) -> Spanned<Expr> {
+        let mut view = StrView::new(source);
+
+        build(resolved_tokens(
+            parse_raw_tokens(&mut view, interner).unwrap(),
+        ))
+        .unwrap()
+    }
+
+    fn statement(tree: Spanned<Expr>) -> Spanned<Expr> {
+        match tree.inner {
+            Expr::Seq(mut statements) => statements.remove(0),
+            _ => tree,
+        }
+    }
+
+    #[test]
+    fn folding() {
+        let mut interner = Interner::new();
+
+        let x = interner.insert("x");
+
+        let folded = statement(optimize(
+            tree(&mut interner, "x * (2 * 3 + 4)\n"),
+            NumericPolicy::Ieee,
+        ));
+
+        let Expr::Binary {
+            op: BinaryOp::Mul,
+            left,
+            right,
+        } = folded.inner
+        else {
+            panic!("expected a product");
+        };
+
+        assert_eq!(left.inner, Expr::Ident(x));
+        assert_eq!(right.inner, Expr::Number(10.0));
+        assert_eq!((right.span.start, right.span.end), (5, 14));
+
+        for (source, expected) in [
+            ("-(2 ^ 3) / 4\n", Expr::Number(-2.0)),
+            ("x * 1\n", Expr::Ident(x)),
+            ("0 + x / (3 - 2)\n", Expr::Ident(x)),
+            ("(x - 0) ^ 1\n", Expr::Ident(x)),
+            ("+x\n", Expr::Ident(x)),
+        ] {
+            let optimized = statement(optimize(tree(&mut interner, source), NumericPolicy::Ieee));
+
+            assert_eq!(optimized.inner, expected, "{source:?}");
+        }
+
+        // under `Strict`, `x * 1` errors for an infinite `x`, so only constants are folded
+        let strict = statement(optimize(
+            tree(&mut interner, "x * (2 - 1)\n"),
+            NumericPolicy::Strict,
+        ));
+
+        let Expr::Binary { left, right, .. } = strict.inner else {
+            panic!("expected the identity to be kept");
+        };
+
+        assert_eq!(left.inner, Expr::Ident(x));
+        assert_eq!(right.inner, Expr::Number(1.0));
+
+        // kept so they still error, or are printed, or resolve `x` before it is reassigned
+        for source in [
+            "1 / 0\n",
+            "0 / 0\n",
+            "x * 1 = 2\n",
+            "(% x) * 1\n",
+            "x * 1 + (x = 2)\n",
+        ] {
+            let original = statement(tree(&mut interner, source));
+            let optimized = statement(optimize(original.clone(), NumericPolicy::Ieee));
+
+            assert!(
+                matches!(optimized.inner, Expr::Binary { .. } | Expr::Assign { .. }),
+                "{source:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn matches_original() {
+        let sources = [
+            "a = 2; b = a * (3 + 1) * 1; % b / 2 ^ 1\n",
+            "x + y * 1\n",
+            "a = 1; a * 1 + (a = 5)\n",
+            "f(x) = x * 1 + 2 * 3; f(4) - 0\n",
+            "(c = 3) * 1 + c\n",
+            "z = 1 / 0\n",
+            "w = 2; % w * 1; + w\n",
+            "1 * 1 = 2\n",
+            "big * 1 - 0\n",
+        ];
+
+        for source in sources {
+            for policy in [NumericPolicy::Ieee, NumericPolicy::Strict] {
+                let mut interner = Interner::new();
+
+                let original = tree(&mut interner, source);
+                let optimized = optimize(original.clone(), policy);
+
+                let mut results = Vec::new();
+
+                for tree in [original, optimized] {
+                    let mut state = State::with_builtins(&mut interner);
+
+                    let buffer = Buffer::default();
+
+                    state.output = Box::new(buffer.clone());
+                    state.numeric = policy;
+                    state.echo = true;
+                    state
+                        .variables
+                        .insert(interner.insert("big"), f64::INFINITY);
+
+                    let result = evaluate(&mut state, &tree);
+
+                    let mut variables = state.variables.into_iter().collect::<Vec<_>>();
+                    variables.sort_by_key(|(id, _)| format!("{id:?}"));
+
+                    results.push((result, buffer.take(), variables));
+                }
+
+                assert_eq!(results[0], results[1], "{source:?} {policy:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::run::Radix;
+
+/// Where the values a program prints go.
+pub trait Output {
+    fn print(&mut self, value: f64, radix: Radix);
+
+    /// Writes a line of text that is not a value, such as the output of a console command.
+    fn line(&mut self, text: &str) {
+        println!("{text}");
+    }
+}
+
+/// Prints values to stdout, each after a space.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stdout;
+
+impl Output for Stdout {
+    fn print(&mut self, value: f64, radix: Radix) {
+        print!(" {}", radix.format(value));
+    }
+}
+
+/// Collects printed values and lines in memory. Clones share the same contents, so one clone
+/// can be given to a `State` and another kept to read them back.
+#[derive(Debug, Clone, Default)]
+pub struct Buffer {
+    values: Rc<RefCell<Vec<f64>>>,
+    lines: Rc<RefCell<Vec<String>>>,
+}
+
+impl Buffer {
+    /// Takes the values printed so far, leaving the buffer empty.
+    pub fn take(&self) -> Vec<f64> {
+        self.values.take()
+    }
+
+    /// Takes the lines written so far.
+    pub fn take_lines(&self) -> Vec<String> {
+        self.lines.take()
+    }
+}
+
+impl Output for Buffer {
+    fn print(&mut self, value: f64, _radix: Radix) {
+        self.values.borrow_mut().push(value);
+    }
+
+    fn line(&mut self, text: &str) {
+        self.lines.borrow_mut().push(text.to_owned());
+    }
+}
+
+impl<F: FnMut(f64, Radix)> Output for F {
+    fn print(&mut self, value: f64, radix: Radix) {
+        self(value, radix)
+    }
+}
@@ -1,11 +1,11 @@
-use std::fmt::Display;
+use std::{cell::Cell, fmt::Display, ops::Range};
 
 use parsr::{
     core::trim::TrimWhitespace,
     input::{Entry, Input, InputExt, InvalidUtf8},
     interner::{Id, Interner},
     parse::{IsParse, ParseError, ParseExt, ParseIterError, ParseMutIter},
-    token::span::Spanned,
+    token::span::{Span, Spanned},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -13,6 +13,8 @@ pub enum RawToken {
     Ident(Id),
     Number(f64),
     Symbol(Symbol),
+    /// A reference to an earlier result, `_1` being the first.
+    History(usize),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -22,90 +24,389 @@ pub enum Symbol {
     Sub,
     Mul,
     Div,
+    Pow,
     LeftParen,
     RightParen,
     Print,
     Semicolon,
+    Comma,
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            Symbol::Equals => '=',
+            Symbol::Add => '+',
+            Symbol::Sub => '-',
+            Symbol::Mul => '*',
+            Symbol::Div => '/',
+            Symbol::Pow => '^',
+            Symbol::LeftParen => '(',
+            Symbol::RightParen => ')',
+            Symbol::Print => '%',
+            Symbol::Semicolon => ';',
+            Symbol::Comma => ',',
+        };
+
+        write!(f, "{symbol}")
+    }
 }
 
 pub fn parse_raw_tokens<'a: 'b, 'b, I: Input>(
     input: &'a mut I,
     interner: &'b mut Interner,
 ) -> Result<
-    impl Iterator<Item = Result<Spanned<RawToken>, ParseIterError<Spanned<UnexpectedCharacter>>>> + 'b,
+    impl Iterator<Item = Result<Spanned<RawToken>, ParseIterError<Spanned<LexError>>>> + 'b,
+    InvalidUtf8,
+> {
+    lex(input, interner, |_| ())
+}
+
+/// Like [`parse_raw_tokens`], also pushing the span of each `#`, `//` or `/* */` comment onto
+/// `comments` as the tokens around it are read.
+pub fn parse_raw_tokens_with_comments<'a: 'b, 'b, I: Input>(
+    input: &'a mut I,
+    interner: &'b mut Interner,
+    comments: &'b mut Vec<Span>,
+) -> Result<
+    impl Iterator<Item = Result<Spanned<RawToken>, ParseIterError<Spanned<LexError>>>> + 'b,
+    InvalidUtf8,
+> {
+    lex(input, interner, |span| comments.push(span))
+}
+
+fn lex<'a: 'b, 'b, I: Input>(
+    input: &'a mut I,
+    interner: &'b mut Interner,
+    mut comment: impl FnMut(Span) + 'b,
+) -> Result<
+    impl Iterator<Item = Result<Spanned<RawToken>, ParseIterError<Spanned<LexError>>>> + 'b,
     InvalidUtf8,
 > {
-    let parser = ParseRawToken.mapped_mut(|token: RawTokenInput| {
+    let parser = ParseRawToken.mapped_mut(move |token: RawTokenInput| {
         let span = match &token {
             RawTokenInput::Alphabetic(entry) => entry.span(),
             RawTokenInput::Numeric(spanned) => spanned.span,
             RawTokenInput::Symbol(spanned) => spanned.span,
+            RawTokenInput::Comment(spanned) => spanned.span,
         };
 
-        Spanned::new(
-            match token {
-                RawTokenInput::Alphabetic(entry) => {
-                    let id = interner.insert(entry.get());
+        let token = match token {
+            RawTokenInput::Alphabetic(entry) => {
+                let token = match history_index(entry.get()) {
+                    Some(index) => RawToken::History(index),
+                    None => RawToken::Ident(interner.insert(entry.get())),
+                };
+
+                entry.consume();
+
+                token
+            }
+            RawTokenInput::Numeric(num) => RawToken::Number(num.inner),
+            RawTokenInput::Symbol(sym) => RawToken::Symbol(sym.inner),
+            RawTokenInput::Comment(_) => {
+                comment(span);
 
-                    entry.consume();
+                return None;
+            }
+        };
 
-                    RawToken::Ident(id)
-                }
-                RawTokenInput::Numeric(num) => RawToken::Number(num.inner),
-                RawTokenInput::Symbol(sym) => RawToken::Symbol(sym.inner),
-            },
-            span,
-        )
+        Some(Spanned::new(token, span))
     });
 
     ParseMutIter::new(input, TrimWhitespace, parser)
+        .map(|tokens| tokens.filter_map(Result::transpose))
 }
 
 pub enum RawTokenInput<'a> {
     Alphabetic(Entry<'a>),
     Numeric(Spanned<f64>),
     Symbol(Spanned<Symbol>),
+    Comment(Spanned<()>),
 }
 
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct ParseRawToken;
 
-#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
-pub struct UnexpectedCharacter;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LexError {
+    UnexpectedCharacter,
+    MalformedNumber,
+    UnterminatedComment,
+}
 
-impl Display for UnexpectedCharacter {
+impl Display for LexError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Unexpected character")
+        match self {
+            LexError::UnexpectedCharacter => write!(f, "Unexpected character"),
+            LexError::MalformedNumber => write!(f, "Malformed number"),
+            LexError::UnterminatedComment => write!(f, "Unterminated comment"),
+        }
+    }
+}
+
+/// Identifiers start with an XID_Start character or `_`.
+pub fn is_ident_start(c: char) -> bool {
+    unicode_ident::is_xid_start(c) || c == '_'
+}
+
+/// Identifiers continue with XID_Continue characters, which include digits, `_` and combining
+/// marks.
+pub fn is_ident_continue(c: char) -> bool {
+    unicode_ident::is_xid_continue(c)
+}
+
+/// Reads exactly `count` characters.
+fn read_chars<'a, I: ?Sized + Input>(
+    input: &'a mut I,
+    count: usize,
+) -> Result<Entry<'a>, ParseError<Spanned<LexError>>> {
+    let read = Cell::new(0);
+
+    let entry = input.read_until_entry(8, |_| {
+        let n = read.get();
+        read.set(n + 1);
+        n == count
+    })?;
+
+    Ok(entry.unsize())
+}
+
+/// The index of a `_N` history reference, which is reserved rather than an identifier.
+fn history_index(ident: &str) -> Option<usize> {
+    let digits = ident.strip_prefix('_')?;
+
+    match digits.chars().all(|c| c.is_ascii_digit()) {
+        true => digits.parse().ok(),
+        false => None,
     }
 }
 
+fn starts_with<I: ?Sized + Input>(input: &mut I, prefix: &str) -> bool {
+    read_chars(input, prefix.chars().count()).is_ok_and(|entry| entry.get() == prefix)
+}
+
+/// Reads a `#` or `//` comment up to the end of the line.
+fn line_comment<'a, I: ?Sized + Input>(
+    input: &'a mut I,
+) -> Result<RawTokenInput<'a>, ParseError<Spanned<LexError>>> {
+    let entry = input.read_until_entry(8, |c| c == '\n')?;
+
+    let comment = entry.spanned(());
+
+    entry.consume();
+
+    Ok(RawTokenInput::Comment(comment))
+}
+
+/// Reads a `/* */` comment, which may nest.
+fn block_comment<'a, I: ?Sized + Input>(
+    input: &'a mut I,
+) -> Result<RawTokenInput<'a>, ParseError<Spanned<LexError>>> {
+    let opening = read_chars(input, 2)?;
+
+    let opening_span = opening.span();
+
+    opening.consume();
+
+    let depth = Cell::new(1usize);
+    let previous = Cell::new('\0');
+
+    let body = input.read_until_entry(8, |c| {
+        if depth.get() == 0 {
+            return true;
+        }
+
+        match (previous.get(), c) {
+            ('/', '*') => {
+                depth.set(depth.get() + 1);
+                previous.set('\0');
+            }
+            ('*', '/') => {
+                depth.set(depth.get() - 1);
+                previous.set('\0');
+            }
+            _ => previous.set(c),
+        }
+
+        false
+    });
+
+    match body {
+        Ok(body) if depth.get() == 0 => {
+            let comment = opening_span.from_self_to_other(body.span()).over(());
+
+            body.consume();
+
+            Ok(RawTokenInput::Comment(comment))
+        }
+        _ => Err(ParseError::new(
+            opening_span.over(LexError::UnterminatedComment),
+        )),
+    }
+}
+
+/// Parses a decimal literal such as `1_000`, `.5` or `2.5e-3`, returning the range of characters
+/// that are malformed on failure.
+fn parse_decimal(text: &str) -> Result<f64, Range<usize>> {
+    let chars = text.chars().collect::<Vec<_>>();
+
+    let is_separator = |index: usize| {
+        index > 0
+            && chars[index - 1].is_ascii_digit()
+            && chars.get(index + 1).is_some_and(|c| c.is_ascii_digit())
+    };
+
+    let mut digits = String::with_capacity(chars.len());
+    let mut index = 0;
+    let mut seen_digit = false;
+    let mut seen_dot = false;
+
+    while let Some(&c) = chars.get(index) {
+        match c {
+            '0'..='9' => {
+                digits.push(c);
+                seen_digit = true;
+            }
+            '_' if is_separator(index) => (),
+            '.' if !seen_dot => {
+                digits.push(c);
+                seen_dot = true;
+            }
+            'e' | 'E' if seen_digit => break,
+            _ => return Err(index..chars.len()),
+        }
+
+        index += 1;
+    }
+
+    if !seen_digit {
+        return Err(0..chars.len());
+    }
+
+    if index < chars.len() {
+        let exponent = index;
+
+        digits.push('e');
+        index += 1;
+
+        if let Some(&sign @ ('+' | '-')) = chars.get(index) {
+            digits.push(sign);
+            index += 1;
+        }
+
+        let start = index;
+
+        while let Some(&c) = chars.get(index) {
+            match c {
+                '0'..='9' => digits.push(c),
+                '_' if is_separator(index) => (),
+                _ => return Err(index..chars.len()),
+            }
+
+            index += 1;
+        }
+
+        if index == start {
+            return Err(exponent..chars.len());
+        }
+    }
+
+    digits.parse().map_err(|_| 0..chars.len())
+}
+
+/// Parses the digits of a hexadecimal, binary or octal literal, returning the range of the first
+/// invalid digit on failure.
+fn parse_integer(text: &str, radix: u32) -> Result<f64, Range<usize>> {
+    let chars = text.chars().collect::<Vec<_>>();
+
+    let is_digit = |index: usize| chars.get(index).is_some_and(|c| c.is_digit(radix));
+
+    let mut value = 0.0;
+
+    for (index, &c) in chars.iter().enumerate() {
+        match c.to_digit(radix) {
+            Some(digit) => value = value * radix as f64 + digit as f64,
+            None if c == '_' && index > 0 && is_digit(index - 1) && is_digit(index + 1) => (),
+            None => return Err(index..index + 1),
+        }
+    }
+
+    Ok(value)
+}
+
+/// Parses any numeric literal, dispatching on the `0x`, `0b` and `0o` prefixes.
+fn parse_number(text: &str) -> Result<f64, Range<usize>> {
+    let radix = match text.get(..2) {
+        Some("0x" | "0X") => 16,
+        Some("0b" | "0B") => 2,
+        Some("0o" | "0O") => 8,
+        _ => return parse_decimal(text),
+    };
+
+    // A prefix without any digits
+    if text.len() == 2 {
+        return Err(0..2);
+    }
+
+    parse_integer(&text[2..], radix).map_err(|invalid| invalid.start + 2..invalid.end + 2)
+}
+
 impl<'a> IsParse<'a> for ParseRawToken {
     type Output = RawTokenInput<'a>;
-    type Error = Spanned<UnexpectedCharacter>;
+    type Error = Spanned<LexError>;
 
     fn __parse<I: ?Sized + Input>(
         self,
         input: &'a mut I,
     ) -> Result<Self::Output, parsr::parse::ParseError<Self::Error>> {
         match input.peek()? {
-            c if c.is_alphabetic() => {
-                let entry = input.read_until_entry(8, |c| !char::is_alphabetic(c))?;
+            c if is_ident_start(c) => {
+                let entry = input.read_until_entry(8, |c| !is_ident_continue(c))?;
 
                 Ok(RawTokenInput::Alphabetic(entry.unsize()))
             }
-            c if c.is_numeric() => {
-                let entry = input.read_until_entry(8, |c| !(char::is_numeric(c) || c == '.'))?;
+            '#' => line_comment(input),
+            '/' if starts_with(input, "//") => line_comment(input),
+            '/' if starts_with(input, "/*") => block_comment(input),
+            c if c.is_ascii_digit() || c == '.' => {
+                let malformed = {
+                    let previous = Cell::new('\0');
+                    let prefixed = Cell::new(false);
 
-                let num = entry.spanned(
-                    entry
-                        .get()
-                        .parse::<f64>()
-                        .map_err(|_| ParseError::new(entry.spanned(UnexpectedCharacter)))?,
-                );
+                    // Reads anything that could be part of a number, so that `12abc` is reported
+                    // as a single malformed number
+                    let entry = input.read_until_entry(8, |c| {
+                        let previous = previous.replace(c);
 
-                entry.consume();
+                        if previous == '0' && matches!(c, 'x' | 'X' | 'b' | 'B' | 'o' | 'O') {
+                            prefixed.set(true);
+                        }
+
+                        let exponent_sign = matches!(c, '+' | '-')
+                            && matches!(previous, 'e' | 'E')
+                            && !prefixed.get();
+
+                        !(c.is_alphanumeric() || c == '_' || c == '.' || exponent_sign)
+                    })?;
+
+                    match parse_number(entry.get()) {
+                        Ok(num) => {
+                            let num = entry.spanned(num);
 
-                Ok(RawTokenInput::Numeric(num))
+                            entry.consume();
+
+                            return Ok(RawTokenInput::Numeric(num));
+                        }
+                        Err(malformed) => malformed,
+                    }
+                };
+
+                read_chars(input, malformed.start)?.consume();
+
+                let entry = read_chars(input, malformed.len())?;
+
+                Err(ParseError::new(entry.spanned(LexError::MalformedNumber)))
             }
             _ => {
                 let entry = input.peek_entry()?;
@@ -116,11 +417,17 @@ impl<'a> IsParse<'a> for ParseRawToken {
                     '-' => Symbol::Sub,
                     '*' => Symbol::Mul,
                     '/' => Symbol::Div,
+                    '^' => Symbol::Pow,
                     '(' => Symbol::LeftParen,
                     ')' => Symbol::RightParen,
                     '%' => Symbol::Print,
                     ';' => Symbol::Semicolon,
-                    _ => return Err(ParseError::new(entry.spanned(UnexpectedCharacter))),
+                    ',' => Symbol::Comma,
+                    _ => {
+                        return Err(ParseError::new(
+                            entry.spanned(LexError::UnexpectedCharacter),
+                        ));
+                    }
                 }));
 
                 entry.consume();
@@ -162,4 +469,231 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn numbers() {
+        let mut interner = Interner::new();
+
+        let mut input = StrView::new("1e-9 2.5E3 .5 1_000_000 7. 3e+2\n");
+
+        let tokens = parse_raw_tokens(&mut input, &mut interner)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|r| r.inner)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens,
+            vec![
+                RawToken::Number(1e-9),
+                RawToken::Number(2500.0),
+                RawToken::Number(0.5),
+                RawToken::Number(1_000_000.0),
+                RawToken::Number(7.0),
+                RawToken::Number(300.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_numbers() {
+        for (source, start, end) in [
+            ("1.2.3\n", 3, 5),
+            ("12abc\n", 2, 5),
+            ("1e+\n", 1, 3),
+            (".\n", 0, 1),
+            ("1_\n", 1, 2),
+            ("0b102\n", 4, 5),
+            ("0o78\n", 3, 4),
+            ("0x\n", 0, 2),
+            ("0x_1\n", 2, 3),
+        ] {
+            let mut interner = Interner::new();
+
+            let mut input = StrView::new(source);
+
+            let error = parse_raw_tokens(&mut input, &mut interner)
+                .unwrap()
+                .find_map(Result::err)
+                .unwrap()
+                .spanned();
+
+            assert_eq!((error.span.start, error.span.end), (start, end), "{source}");
+        }
+    }
+
+    #[test]
+    fn radix_numbers() {
+        let mut interner = Interner::new();
+
+        let mut input = StrView::new("0xFF 0b1010 0o755 0xdead_beef 0xE-1\n");
+
+        let tokens = parse_raw_tokens(&mut input, &mut interner)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|r| r.inner)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens,
+            vec![
+                RawToken::Number(255.0),
+                RawToken::Number(10.0),
+                RawToken::Number(493.0),
+                RawToken::Number(3735928559.0),
+                RawToken::Number(14.0),
+                RawToken::Symbol(Symbol::Sub),
+                RawToken::Number(1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn identifiers() {
+        let mut interner = Interner::new();
+
+        let mut input = StrView::new("x1 = total_cost * v2_rate + _tmp - été + x1\n");
+
+        let tokens = parse_raw_tokens(&mut input, &mut interner)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|r| r.inner)
+            .collect::<Vec<_>>();
+
+        let [x1, total_cost, v2_rate, tmp, ete] =
+            ["x1", "total_cost", "v2_rate", "_tmp", "été"].map(|name| interner.insert(name));
+
+        assert_eq!(
+            tokens,
+            vec![
+                RawToken::Ident(x1),
+                RawToken::Symbol(Symbol::Equals),
+                RawToken::Ident(total_cost),
+                RawToken::Symbol(Symbol::Mul),
+                RawToken::Ident(v2_rate),
+                RawToken::Symbol(Symbol::Add),
+                RawToken::Ident(tmp),
+                RawToken::Symbol(Symbol::Sub),
+                RawToken::Ident(ete),
+                RawToken::Symbol(Symbol::Add),
+                RawToken::Ident(x1),
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let mut interner = Interner::new();
+
+        // `é` as `e` and a combining acute accent
+        let mut input = StrView::new("cafe\u{301} + \u{3c0}r\u{b2}\n");
+
+        let tokens = parse_raw_tokens(&mut input, &mut interner)
+            .unwrap()
+            .collect::<Vec<_>>();
+
+        let cafe = interner.insert("cafe\u{301}");
+        let pi_r = interner.insert("\u{3c0}r");
+
+        assert_eq!(tokens[0].map(|r| r.inner), Ok(RawToken::Ident(cafe)));
+        assert_eq!(tokens[2].map(|r| r.inner), Ok(RawToken::Ident(pi_r)));
+
+        // `²` is a number but not a digit, so it cannot continue an identifier
+        let error = tokens[3].unwrap_err().spanned();
+
+        assert_eq!(error.inner, ParseIterError(LexError::UnexpectedCharacter));
+        assert_eq!((error.span.start, error.span.end), (10, 11));
+    }
+
+    #[test]
+    fn comments() {
+        let mut interner = Interner::new();
+
+        let mut input = StrView::new("a = 1 # one\nb /* x /* y */ z */ = // two\n2 / 1\n");
+
+        let tokens = parse_raw_tokens(&mut input, &mut interner)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|r| r.inner)
+            .collect::<Vec<_>>();
+
+        let a = interner.insert("a");
+        let b = interner.insert("b");
+
+        assert_eq!(
+            tokens,
+            vec![
+                RawToken::Ident(a),
+                RawToken::Symbol(Symbol::Equals),
+                RawToken::Number(1.0),
+                RawToken::Ident(b),
+                RawToken::Symbol(Symbol::Equals),
+                RawToken::Number(2.0),
+                RawToken::Symbol(Symbol::Div),
+                RawToken::Number(1.0),
+            ]
+        );
+
+        let mut input = StrView::new("1 /* a /* b */ c */ 2 # d\n");
+        let mut comments = Vec::new();
+
+        let tokens = parse_raw_tokens_with_comments(&mut input, &mut interner, &mut comments)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|r| r.inner)
+            .collect::<Vec<_>>();
+
+        assert_eq!(tokens, vec![RawToken::Number(1.0), RawToken::Number(2.0)]);
+        assert_eq!(
+            comments
+                .iter()
+                .map(|span| (span.start, span.end))
+                .collect::<Vec<_>>(),
+            vec![(2, 19), (22, 25)]
+        );
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        let mut interner = Interner::new();
+
+        let mut input = StrView::new("1 /* a /* b */\n");
+
+        let error = parse_raw_tokens(&mut input, &mut interner)
+            .unwrap()
+            .find_map(Result::err)
+            .unwrap()
+            .spanned();
+
+        assert_eq!((error.span.start, error.span.end), (2, 4));
+    }
+
+    #[test]
+    fn history() {
+        let mut interner = Interner::new();
+
+        let mut input = StrView::new("_1 + _12 * _ + _1a\n");
+
+        let tokens = parse_raw_tokens(&mut input, &mut interner)
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|r| r.inner)
+            .collect::<Vec<_>>();
+
+        let underscore = interner.insert("_");
+        let ident = interner.insert("_1a");
+
+        assert_eq!(
+            tokens,
+            vec![
+                RawToken::History(1),
+                RawToken::Symbol(Symbol::Add),
+                RawToken::History(12),
+                RawToken::Symbol(Symbol::Mul),
+                RawToken::Ident(underscore),
+                RawToken::Symbol(Symbol::Add),
+                RawToken::Ident(ident),
+            ]
+        );
+    }
 }
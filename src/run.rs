@@ -1,18 +1,159 @@
-use std::{fmt::Display, iter};
+use std::{fmt::Display, iter, rc::Rc};
 
 use gxhash::{HashMap, HashMapExt};
-use parsr::{interner::Id, token::span::Spanned};
+use parsr::{
+    interner::{Id, Interner},
+    token::span::{Span, Spanned},
+};
 
-use crate::tokens::{Operator, Token, TokenError, Value};
+use crate::{
+    functions::{Arity, Function, Native, UserFunction, register_builtins},
+    output::{Output, Stdout},
+    tokens::{Operator, ProcessorError, Token, TokenError, Value},
+};
 
 pub struct State {
     pub variables: HashMap<Id, f64>,
+    pub functions: HashMap<Id, Function>,
+    pub numeric: NumericPolicy,
+    pub radix: Radix,
+    pub atomicity: Atomicity,
+    /// Where printed values go
+    pub output: Box<dyn Output>,
+    /// Whether statements print their result, except for assignments and prints
+    pub echo: bool,
+    /// The variable set to the result of each statement, if any
+    pub ans: Option<Id>,
+    /// Whether the result of every statement is kept in `history`. Only the console turns this
+    /// on, since a host running many sources would keep every result forever.
+    pub record_history: bool,
+    /// The result of every statement, referenced as `_1`, `_2`, ...
+    pub history: Vec<f64>,
+    /// How deeply user functions may call each other before erroring
+    pub max_call_depth: usize,
+    /// The parameters of each user function currently being called
+    frames: Vec<HashMap<Id, f64>>,
+    /// How to undo the run in progress, when `atomicity` asks for it
+    journal: Option<Journal>,
+    /// The builtin or host functions replaced by a user function, restored when it's removed
+    shadowed: HashMap<Id, Function>,
 }
 
 impl State {
     pub fn new() -> Self {
         Self {
             variables: HashMap::new(),
+            functions: HashMap::new(),
+            numeric: NumericPolicy::default(),
+            radix: Radix::default(),
+            atomicity: Atomicity::default(),
+            output: Box::new(Stdout),
+            echo: false,
+            ans: None,
+            record_history: false,
+            history: Vec::new(),
+            max_call_depth: 256,
+            frames: Vec::new(),
+            journal: None,
+            shadowed: HashMap::new(),
+        }
+    }
+
+    pub fn with_builtins(interner: &mut Interner) -> Self {
+        let mut state = Self::new();
+
+        register_builtins(&mut state.functions, interner);
+
+        state
+    }
+
+    /// Registers a function implemented by the host program, replacing any function with the
+    /// same name.
+    pub fn register(
+        &mut self,
+        interner: &mut Interner,
+        name: &str,
+        arity: Arity,
+        call: impl Fn(&[f64]) -> Result<f64, String> + 'static,
+    ) {
+        let id = interner.insert(name);
+
+        self.shadowed.remove(&id);
+        self.functions
+            .insert(id, Function::Native(Native::new(arity, call)));
+    }
+
+    /// Removes every variable and user function, keeping the builtins and settings. Builtins a
+    /// user function replaced are restored.
+    pub fn clear(&mut self) {
+        self.variables.clear();
+        self.functions
+            .retain(|_, function| !matches!(function, Function::User(_)));
+        self.functions.extend(self.shadowed.drain());
+    }
+
+    /// Removes a user function, restoring the builtin it replaced if there was one.
+    pub fn remove_function(&mut self, id: Id) -> Option<Rc<UserFunction>> {
+        let function = match self.functions.get(&id) {
+            Some(Function::User(function)) => Rc::clone(function),
+            _ => return None,
+        };
+
+        self.functions.remove(&id);
+
+        if let Some(shadowed) = self.shadowed.remove(&id) {
+            self.functions.insert(id, shadowed);
+        }
+
+        Some(function)
+    }
+
+    /// Looks a variable up in the innermost call, falling back to the globals.
+    pub fn lookup(&self, id: Id) -> Option<f64> {
+        self.frames
+            .last()
+            .and_then(|frame| frame.get(&id))
+            .or_else(|| self.variables.get(&id))
+            .copied()
+    }
+
+    /// Assigns a variable in the innermost call, or globally outside of any call.
+    pub fn assign(&mut self, id: Id, value: f64) {
+        match self.frames.last_mut() {
+            Some(frame) => {
+                frame.insert(id, value);
+            }
+            None => self.set_variable(id, value),
+        }
+    }
+
+    /// Sets a global variable, recording its old value if the run may be undone.
+    pub(crate) fn set_variable(&mut self, id: Id, value: f64) {
+        let old = self.variables.insert(id, value);
+
+        if let Some(journal) = &mut self.journal {
+            journal.undo.push(Undo::Variable(id, old));
+        }
+    }
+
+    /// Defines a function, recording the one it replaces if the run may be undone.
+    pub(crate) fn set_function(&mut self, id: Id, function: Function) {
+        let old = self.functions.insert(id, function);
+
+        if let Some(old @ (Function::Builtin(_) | Function::Native(_))) = &old {
+            self.shadowed.insert(id, old.clone());
+        }
+
+        if let Some(journal) = &mut self.journal {
+            journal.undo.push(Undo::Function(id, old));
+        }
+    }
+
+    /// Keeps everything the run has done so far, so an error only undoes what comes after.
+    pub(crate) fn commit(&mut self) {
+        if let Some(journal) = &mut self.journal {
+            journal.undo.clear();
+            journal.history = self.history.len();
         }
     }
 }
@@ -23,6 +164,103 @@ impl Default for State {
     }
 }
 
+/// How arithmetic treats results that are not finite numbers.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NumericPolicy {
+    /// Results follow IEEE 754, so `1 / 0` is `inf` and `0 / 0` is `NaN`.
+    #[default]
+    Ieee,
+    /// Division by zero, overflow to infinity and `NaN` results are errors.
+    Strict,
+}
+
+/// How much of a run is undone when it errors.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Atomicity {
+    /// Everything before the error stays applied.
+    #[default]
+    Off,
+    /// A run either applies all of its assignments and definitions or none of them.
+    Line,
+    /// Each `;` separated statement either applies entirely or not at all.
+    Statement,
+}
+
+/// A variable or function as it was before a run changed it, `None` if it did not exist.
+enum Undo {
+    Variable(Id, Option<f64>),
+    Function(Id, Option<Function>),
+}
+
+/// The changes a run has made since it started or was last committed, to undo when it errors.
+struct Journal {
+    undo: Vec<Undo>,
+    history: usize,
+}
+
+impl Journal {
+    fn rollback(self, state: &mut State) {
+        for undo in self.undo.into_iter().rev() {
+            match undo {
+                Undo::Variable(id, Some(value)) => {
+                    state.variables.insert(id, value);
+                }
+                Undo::Variable(id, None) => {
+                    state.variables.remove(&id);
+                }
+                Undo::Function(id, Some(function)) => {
+                    state.functions.insert(id, function);
+                }
+                Undo::Function(id, None) => {
+                    state.functions.remove(&id);
+                }
+            }
+        }
+
+        state.history.truncate(self.history);
+    }
+}
+
+/// The base printed values are shown in.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Radix {
+    Binary,
+    Octal,
+    #[default]
+    Decimal,
+    Hexadecimal,
+}
+
+impl Radix {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "2" | "bin" => Some(Radix::Binary),
+            "8" | "oct" => Some(Radix::Octal),
+            "10" | "dec" => Some(Radix::Decimal),
+            "16" | "hex" => Some(Radix::Hexadecimal),
+            _ => None,
+        }
+    }
+
+    /// Formats a value as a literal in this radix, falling back to decimal for values that are
+    /// not integers.
+    pub fn format(self, value: f64) -> String {
+        if self == Radix::Decimal || value.fract() != 0.0 || value.abs() >= u64::MAX as f64 {
+            return value.to_string();
+        }
+
+        let sign = if value < 0.0 { "-" } else { "" };
+        let magnitude = value.abs() as u64;
+
+        match self {
+            Radix::Binary => format!("{sign}0b{magnitude:b}"),
+            Radix::Octal => format!("{sign}0o{magnitude:o}"),
+            Radix::Decimal => unreachable!(),
+            Radix::Hexadecimal => format!("{sign}0x{magnitude:X}"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Stack {
     Value(f64),
@@ -52,7 +290,24 @@ pub enum RunError {
     AssigningToNull,
     AttemptedToUseNull,
     AttemptedToPrintNull,
-    // DivisionByZero,
+    DivisionByZero,
+    Overflow,
+    NotANumber,
+    UnknownFunction,
+    ArityMismatch {
+        expected: Arity,
+        found: usize,
+    },
+    CallDepthExceeded,
+    /// A definition inside a function body, which only hand-built tokens can contain
+    NestedDefinition,
+    /// A definition parameter that is not a name, which only hand-built tokens can contain
+    InvalidParameter,
+    UnknownHistory,
+    /// A `_N` reference when `State::record_history` is off
+    NoHistory,
+    /// An error returned by a function registered by the host program
+    Native(String),
 }
 
 impl Display for RunError {
@@ -63,180 +318,1006 @@ impl Display for RunError {
             RunError::AssigningToNull => write!(f, "Cannot assign to NULL"),
             RunError::AttemptedToUseNull => write!(f, "Cannot use NULL"),
             RunError::AttemptedToPrintNull => write!(f, "Cannot print NULL"),
-            // RunError::DivisionByZero => write!(f, "Cannot divide by zero"),
+            RunError::DivisionByZero => write!(f, "Cannot divide by zero"),
+            RunError::Overflow => write!(f, "Result is too large"),
+            RunError::NotANumber => write!(f, "Result is not a number"),
+            RunError::UnknownFunction => write!(f, "Unknown function"),
+            RunError::ArityMismatch { expected, found } => {
+                write!(f, "Expected {expected} but found {found}")
+            }
+            RunError::CallDepthExceeded => write!(f, "Maximum call depth exceeded"),
+            RunError::NestedDefinition => {
+                write!(f, "Functions can only be defined at the top level")
+            }
+            RunError::InvalidParameter => write!(f, "Parameters must be names"),
+            RunError::UnknownHistory => write!(f, "There is no result with this index"),
+            RunError::NoHistory => write!(f, "Earlier results are only kept in the console"),
+            RunError::Native(e) => write!(f, "{e}"),
         }
     }
 }
 
+/// Resolves a stack entry to the number it holds, looking up identifiers.
+pub(crate) fn number(
+    state: &State,
+    entry: Spanned<Stack>,
+) -> Result<Spanned<f64>, Spanned<RunErrorContainer>> {
+    Ok(entry.span.over(match entry.inner {
+        Stack::Value(num) => num,
+        Stack::Ident(id) => match state.lookup(id) {
+            Some(v) => v,
+            None => {
+                return Err(entry
+                    .span
+                    .over(RunErrorContainer::RunError(RunError::UnassignedVariable)));
+            }
+        },
+        Stack::Null => {
+            return Err(entry
+                .span
+                .over(RunErrorContainer::RunError(RunError::AttemptedToUseNull)));
+        }
+    }))
+}
+
+/// Pops the operand of the operator at `span`, which resolved tokens always leave.
+fn pop(
+    stack: &mut Vec<Spanned<Stack>>,
+    span: Span,
+) -> Result<Spanned<Stack>, Spanned<RunErrorContainer>> {
+    stack.pop().ok_or_else(|| {
+        span.over(RunErrorContainer::TokenError(TokenError::ProcessorError(
+            ProcessorError::ExpectedExpression,
+        )))
+    })
+}
+
 fn pop_number(
     state: &mut State,
     stack: &mut Vec<Spanned<Stack>>,
+    span: Span,
 ) -> Result<Spanned<f64>, Spanned<RunErrorContainer>> {
-    let num_stack = stack.pop().unwrap();
+    number(state, pop(stack, span)?)
+}
 
-    Ok(num_stack.span.over(match num_stack.inner {
-        Stack::Value(num) => num,
-        Stack::Ident(id) => match state.variables.get(&id) {
-            Some(v) => *v,
+/// Pops the arguments of the call at `span`, spanning from the name to the last argument.
+fn pop_arguments(
+    state: &mut State,
+    stack: &mut Vec<Spanned<Stack>>,
+    span: Span,
+    count: usize,
+) -> Result<Spanned<Vec<f64>>, Spanned<RunErrorContainer>> {
+    let mut arguments = Vec::with_capacity(count);
+
+    let mut call = span;
+
+    for _ in 0..count {
+        let argument = pop_number(state, stack, span)?;
+
+        if arguments.is_empty() {
+            call = call.from_self_to_other(argument.span);
+        }
+
+        arguments.push(argument.inner);
+    }
+
+    arguments.reverse();
+
+    Ok(call.over(arguments))
+}
+
+pub(crate) fn checked(
+    state: &State,
+    result: Spanned<f64>,
+) -> Result<Spanned<Stack>, Spanned<RunErrorContainer>> {
+    if state.numeric == NumericPolicy::Strict {
+        if result.inner.is_nan() {
+            return Err(result
+                .span
+                .over(RunErrorContainer::RunError(RunError::NotANumber)));
+        }
+
+        if result.inner.is_infinite() {
+            return Err(result
+                .span
+                .over(RunErrorContainer::RunError(RunError::Overflow)));
+        }
+    }
+
+    Ok(result.span.over(Stack::Value(result.inner)))
+}
+
+pub(crate) fn call(
+    state: &mut State,
+    function: Spanned<Id>,
+    arguments: &[f64],
+) -> Result<Spanned<Stack>, Spanned<RunErrorContainer>> {
+    let Some(found) = state.functions.get(&function.inner).cloned() else {
+        return Err(function
+            .span
+            .over(RunErrorContainer::RunError(RunError::UnknownFunction)));
+    };
+
+    match found {
+        Function::Builtin(builtin) => {
+            if !builtin.arity.accepts(arguments.len()) {
+                return Err(function.span.over(RunErrorContainer::RunError(
+                    RunError::ArityMismatch {
+                        expected: builtin.arity,
+                        found: arguments.len(),
+                    },
+                )));
+            }
+
+            checked(state, function.span.over((builtin.call)(arguments)))
+        }
+        Function::Native(native) => {
+            if !native.arity.accepts(arguments.len()) {
+                return Err(function.span.over(RunErrorContainer::RunError(
+                    RunError::ArityMismatch {
+                        expected: native.arity,
+                        found: arguments.len(),
+                    },
+                )));
+            }
+
+            match (native.call)(arguments) {
+                Ok(result) => checked(state, function.span.over(result)),
+                Err(e) => Err(function
+                    .span
+                    .over(RunErrorContainer::RunError(RunError::Native(e)))),
+            }
+        }
+        Function::User(user) => {
+            let depth = state.frames.len();
+
+            let result = call_user(state, function.span, user, arguments);
+
+            state.frames.truncate(depth);
+
+            // The body was written on another line, so its spans mean nothing at the call site
+            result
+                .map(|num| function.span.over(Stack::Value(num)))
+                .map_err(|e| function.span.over(e.inner))
+        }
+    }
+}
+
+/// A call of a user function that is being run.
+struct Frame {
+    function: Rc<UserFunction>,
+    /// The next token of the body to run
+    next: usize,
+    stack: Vec<Spanned<Stack>>,
+    /// Where the call is in the body of its caller
+    span: Span,
+}
+
+/// Checks a call of a user function and gives its parameters their own scope.
+fn enter(
+    state: &mut State,
+    span: Span,
+    function: &UserFunction,
+    arguments: &[f64],
+) -> Result<(), Spanned<RunErrorContainer>> {
+    if function.params.len() != arguments.len() {
+        return Err(
+            span.over(RunErrorContainer::RunError(RunError::ArityMismatch {
+                expected: Arity::Exact(function.params.len()),
+                found: arguments.len(),
+            })),
+        );
+    }
+
+    if state.frames.len() >= state.max_call_depth {
+        return Err(span.over(RunErrorContainer::RunError(RunError::CallDepthExceeded)));
+    }
+
+    let mut frame = HashMap::with_capacity(arguments.len());
+
+    for (&param, &argument) in function.params.iter().zip(arguments) {
+        frame.insert(param, argument);
+    }
+
+    state.frames.push(frame);
+
+    Ok(())
+}
+
+/// Runs a user function and every user function it calls on a stack of frames rather than by
+/// recursing, so that only `max_call_depth` limits how deeply they call each other.
+fn call_user(
+    state: &mut State,
+    span: Span,
+    function: Rc<UserFunction>,
+    arguments: &[f64],
+) -> Result<f64, Spanned<RunErrorContainer>> {
+    enter(state, span, &function, arguments)?;
+
+    let mut frame = Frame {
+        function,
+        next: 0,
+        stack: Vec::new(),
+        span,
+    };
+
+    let mut callers = Vec::<Frame>::new();
+
+    loop {
+        let Some(&token) = frame.function.body.get(frame.next) else {
+            let result = pop_number(state, &mut frame.stack, frame.span)?.inner;
+
+            state.frames.pop();
+
+            let Some(mut caller) = callers.pop() else {
+                return Ok(result);
+            };
+
+            caller.stack.push(frame.span.over(Stack::Value(result)));
+            frame = caller;
+
+            continue;
+        };
+
+        frame.next += 1;
+
+        let call = match token.inner {
+            Token::Value(Value::Call(id)) => Some((id, 0)),
+            Token::Operator(Operator::Call(id, count)) => Some((id, count)),
+            _ => None,
+        };
+
+        if let Some((id, count)) = call
+            && let Some(Function::User(callee)) = state.functions.get(&id)
+        {
+            let callee = callee.clone();
+
+            let arguments = pop_arguments(state, &mut frame.stack, token.span, count)?;
+
+            enter(state, arguments.span, &callee, &arguments.inner)?;
+
+            let callee = Frame {
+                function: callee,
+                next: 0,
+                stack: Vec::new(),
+                span: arguments.span,
+            };
+
+            callers.push(std::mem::replace(&mut frame, callee));
+        } else {
+            step(state, &mut frame.stack, token)?;
+        }
+    }
+}
+
+fn define(
+    state: &mut State,
+    stack: &mut Vec<Spanned<Stack>>,
+    name: Spanned<Id>,
+    params: usize,
+    body: impl Iterator<Item = Result<Spanned<Token>, Spanned<TokenError>>>,
+) -> Result<(), Spanned<RunErrorContainer>> {
+    let mut ids = Vec::with_capacity(params);
+
+    for _ in 0..params {
+        let param = pop(stack, name.span)?;
+
+        match param.inner {
+            Stack::Ident(id) => ids.push(id),
+            _ => {
+                return Err(param
+                    .span
+                    .over(RunErrorContainer::RunError(RunError::InvalidParameter)));
+            }
+        }
+    }
+
+    ids.reverse();
+
+    let body = body
+        .map(|token| token.map_err(|e| e.map(RunErrorContainer::TokenError)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    state.set_function(
+        name.inner,
+        Function::User(Rc::new(UserFunction { params: ids, body })),
+    );
+
+    stack.push(name.span.over(Stack::Null));
+
+    Ok(())
+}
+
+/// Applies `Add`, `Sub`, `Mul`, `Div` or `Pow`.
+pub(crate) fn binary(
+    state: &State,
+    operator: Operator,
+    left: Spanned<f64>,
+    right: Spanned<f64>,
+) -> Result<Spanned<Stack>, Spanned<RunErrorContainer>> {
+    let span = left.span.from_self_to_other(right.span);
+
+    let result = match operator {
+        Operator::Add => left.inner + right.inner,
+        Operator::Sub => left.inner - right.inner,
+        Operator::Mul => left.inner * right.inner,
+        Operator::Div => {
+            if state.numeric == NumericPolicy::Strict && right.inner == 0.0 {
+                return Err(span.over(RunErrorContainer::RunError(RunError::DivisionByZero)));
+            }
+
+            left.inner / right.inner
+        }
+        Operator::Pow => left.inner.powf(right.inner),
+        _ => unreachable!("{operator:?} is not a binary operator"),
+    };
+
+    checked(state, span.over(result))
+}
+
+pub(crate) fn assign(
+    state: &mut State,
+    target: Spanned<Stack>,
+    value: Spanned<f64>,
+) -> Result<Spanned<Stack>, Spanned<RunErrorContainer>> {
+    let var = target.span.over(match target.inner {
+        Stack::Ident(id) => id,
+        Stack::Value(_) => {
+            return Err(target
+                .span
+                .over(RunErrorContainer::RunError(RunError::AssigningToExpression)));
+        }
+        Stack::Null => {
+            return Err(target
+                .span
+                .over(RunErrorContainer::RunError(RunError::AssigningToNull)));
+        }
+    });
+
+    state.assign(var.inner, value.inner);
+
+    Ok(var
+        .span
+        .from_self_to_other(value.span)
+        .over(Stack::Ident(var.inner)))
+}
+
+/// Prints an entry, leaving it as the value of the print.
+pub(crate) fn print(
+    state: &mut State,
+    span: Span,
+    entry: Spanned<Stack>,
+) -> Result<Spanned<Stack>, Spanned<RunErrorContainer>> {
+    let val = match &entry.inner {
+        Stack::Value(num) => *num,
+        Stack::Ident(id) => match state.lookup(*id) {
+            Some(v) => v,
             None => {
-                return Err(num_stack
+                return Err(entry
                     .span
                     .over(RunErrorContainer::RunError(RunError::UnassignedVariable)));
             }
         },
         Stack::Null => {
-            return Err(num_stack
+            return Err(entry
                 .span
-                .over(RunErrorContainer::RunError(RunError::AttemptedToUseNull)));
+                .over(RunErrorContainer::RunError(RunError::AttemptedToPrintNull)));
         }
-    }))
+    };
+
+    state.output.print(val, state.radix);
+
+    Ok(span.from_self_to_other(entry.span).over(entry.inner))
+}
+
+/// Ends a statement with the entry it left, recording its result if it has one.
+pub(crate) fn end_statement(
+    state: &mut State,
+    entry: Option<Spanned<Stack>>,
+) -> Result<Option<f64>, Spanned<RunErrorContainer>> {
+    let result = match entry {
+        Some(entry) if entry.inner != Stack::Null => Some(number(state, entry)?.inner),
+        _ => None,
+    };
+
+    if let Some(result) = result {
+        if state.record_history {
+            state.history.push(result);
+        }
+
+        if let Some(ans) = state.ans {
+            state.set_variable(ans, result);
+        }
+    }
+
+    Ok(result)
 }
 
+fn step(
+    state: &mut State,
+    stack: &mut Vec<Spanned<Stack>>,
+    token: Spanned<Token>,
+) -> Result<(), Spanned<RunErrorContainer>> {
+    match token.inner {
+        Token::Value(Value::Number(num)) => stack.push(token.span.over(Stack::Value(num))),
+        Token::Value(Value::Ident(id)) => stack.push(token.span.over(Stack::Ident(id))),
+        Token::Value(Value::Call(id)) => stack.push(call(state, token.span.over(id), &[])?),
+        Token::Value(Value::History(index)) => stack.push(history(state, token.span, index)?),
+        Token::Operator(operator) => match operator {
+            Operator::Equals => {
+                let num = pop_number(state, stack, token.span)?;
+                let target = pop(stack, token.span)?;
+
+                stack.push(assign(state, target, num)?);
+            }
+            Operator::Add | Operator::Sub | Operator::Mul | Operator::Div | Operator::Pow => {
+                let right = pop_number(state, stack, token.span)?;
+                let left = pop_number(state, stack, token.span)?;
+
+                stack.push(binary(state, operator, left, right)?);
+            }
+            Operator::Neg => {
+                let num = pop_number(state, stack, token.span)?;
+
+                stack.push(
+                    token
+                        .span
+                        .from_self_to_other(num.span)
+                        .over(Stack::Value(-num.inner)),
+                );
+            }
+            Operator::Pos => {
+                let num = pop_number(state, stack, token.span)?;
+
+                stack.push(
+                    token
+                        .span
+                        .from_self_to_other(num.span)
+                        .over(Stack::Value(num.inner)),
+                );
+            }
+            Operator::Comma => {}
+            Operator::Define { .. } => {
+                return Err(token
+                    .span
+                    .over(RunErrorContainer::RunError(RunError::NestedDefinition)));
+            }
+            Operator::Call(id, count) => {
+                let arguments = pop_arguments(state, stack, token.span, count)?;
+
+                stack.push(call(state, arguments.span.over(id), &arguments.inner)?);
+            }
+            Operator::Print => {
+                let popped = pop(stack, token.span)?;
+
+                stack.push(print(state, token.span, popped)?);
+            }
+            Operator::Semicolon => {
+                semicolon(state, stack, token.span)?;
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Ends the statement on top of the stack, leaving a single NULL for every statement so far.
+fn semicolon(
+    state: &mut State,
+    stack: &mut Vec<Spanned<Stack>>,
+    span: Span,
+) -> Result<Option<f64>, Spanned<RunErrorContainer>> {
+    let popped = stack.pop();
+
+    let mut span = popped
+        .as_ref()
+        .map(|s| s.span)
+        .unwrap_or_default()
+        .from_self_to_other(span);
+
+    let result = end_statement(state, popped)?;
+
+    while let Some(Spanned {
+        inner: &Stack::Null,
+        span: earlier_span,
+    }) = stack.last().map(Spanned::as_ref)
+    {
+        span = earlier_span.from_self_to_other(span);
+
+        let _ = stack.pop();
+    }
+
+    stack.push(span.over(Stack::Null));
+
+    Ok(result)
+}
+
+pub(crate) fn history(
+    state: &State,
+    span: Span,
+    index: usize,
+) -> Result<Spanned<Stack>, Spanned<RunErrorContainer>> {
+    if !state.record_history {
+        return Err(span.over(RunErrorContainer::RunError(RunError::NoHistory)));
+    }
+
+    let Some(&value) = index.checked_sub(1).and_then(|i| state.history.get(i)) else {
+        return Err(span.over(RunErrorContainer::RunError(RunError::UnknownHistory)));
+    };
+
+    Ok(span.over(Stack::Value(value)))
+}
+
+/// Runs resolved tokens, returning the result of the last statement that had one.
 pub fn run(
     state: &mut State,
     tokens: impl Iterator<Item = Result<Spanned<Token>, Spanned<TokenError>>>,
-) -> Result<(), Spanned<RunErrorContainer>> {
+) -> Result<Option<f64>, Spanned<RunErrorContainer>> {
+    transaction(state, |state| run_statements(state, tokens))
+}
+
+/// Runs `f`, undoing its changes to the variables and functions if it errors when
+/// `state.atomicity` asks for it. Statement atomicity is kept by `f` calling `State::commit`
+/// after each statement.
+pub(crate) fn transaction<T>(
+    state: &mut State,
+    f: impl FnOnce(&mut State) -> Result<T, Spanned<RunErrorContainer>>,
+) -> Result<T, Spanned<RunErrorContainer>> {
+    state.journal = match state.atomicity {
+        Atomicity::Off => None,
+        Atomicity::Line | Atomicity::Statement => Some(Journal {
+            undo: Vec::new(),
+            history: state.history.len(),
+        }),
+    };
+
+    let result = f(state);
+
+    if let (Err(_), Some(journal)) = (&result, state.journal.take()) {
+        journal.rollback(state);
+    }
+
+    state.journal = None;
+
+    result
+}
+
+fn run_statements(
+    state: &mut State,
+    tokens: impl Iterator<Item = Result<Spanned<Token>, Spanned<TokenError>>>,
+) -> Result<Option<f64>, Spanned<RunErrorContainer>> {
     let mut stack = Vec::<Spanned<Stack>>::new();
 
     let final_semicolon = Ok(Spanned::default_span(Token::Operator(Operator::Semicolon)));
 
-    for token in tokens.chain(iter::once(final_semicolon)) {
+    let mut tokens = tokens.chain(iter::once(final_semicolon));
+
+    // the token before each semicolon decides whether the statement is echoed
+    let mut last = None;
+
+    let mut result = None;
+
+    while let Some(token) = tokens.next() {
         let token = token.map_err(|e| e.map(RunErrorContainer::TokenError))?;
 
-        match token.inner {
-            Token::Value(Value::Number(num)) => stack.push(token.span.over(Stack::Value(num))),
-            Token::Value(Value::Ident(id)) => stack.push(token.span.over(Stack::Ident(id))),
-            Token::Operator(operator) => match operator {
-                Operator::Equals => {
-                    let num = pop_number(state, &mut stack)?;
-
-                    let var_stack = stack.pop().unwrap();
-
-                    let var = var_stack.span.over(match var_stack.inner {
-                        Stack::Ident(id) => id,
-                        Stack::Value(_) => {
-                            return Err(var_stack.span.over(RunErrorContainer::RunError(
-                                RunError::AssigningToExpression,
-                            )));
-                        }
-                        Stack::Null => {
-                            return Err(var_stack
-                                .span
-                                .over(RunErrorContainer::RunError(RunError::AssigningToNull)));
-                        }
-                    });
-
-                    state.variables.insert(var.inner, num.inner);
-
-                    stack.push(
-                        var.span
-                            .from_self_to_other(num.span)
-                            .over(Stack::Ident(var.inner)),
-                    );
-                }
-                Operator::Add => {
-                    let right = pop_number(state, &mut stack)?;
-                    let left = pop_number(state, &mut stack)?;
-
-                    stack.push(
-                        left.span
-                            .from_self_to_other(right.span)
-                            .over(Stack::Value(left.inner + right.inner)),
-                    );
-                }
-                Operator::Sub => {
-                    let right = pop_number(state, &mut stack)?;
-                    let left = pop_number(state, &mut stack)?;
-
-                    stack.push(
-                        left.span
-                            .from_self_to_other(right.span)
-                            .over(Stack::Value(left.inner - right.inner)),
-                    );
-                }
-                Operator::Mul => {
-                    let right = pop_number(state, &mut stack)?;
-                    let left = pop_number(state, &mut stack)?;
-
-                    stack.push(
-                        left.span
-                            .from_self_to_other(right.span)
-                            .over(Stack::Value(left.inner * right.inner)),
-                    );
-                }
-                Operator::Div => {
-                    let right = pop_number(state, &mut stack)?;
-                    let left = pop_number(state, &mut stack)?;
-
-                    stack.push(
-                        left.span
-                            .from_self_to_other(right.span)
-                            .over(Stack::Value(left.inner / right.inner)),
-                    );
-                }
-                Operator::Print => {
-                    let popped = stack.pop().unwrap();
-
-                    let val = match &popped.inner {
-                        Stack::Value(num) => *num,
-                        Stack::Ident(id) => match state.variables.get(id) {
-                            Some(v) => *v,
-                            None => {
-                                return Err(popped.span.over(RunErrorContainer::RunError(
-                                    RunError::UnassignedVariable,
-                                )));
-                            }
-                        },
-                        Stack::Null => {
-                            return Err(popped.span.over(RunErrorContainer::RunError(
-                                RunError::AttemptedToPrintNull,
-                            )));
-                        }
-                    };
-
-                    print!(" {val}");
-
-                    stack.push(
-                        token
-                            .span
-                            .from_self_to_other(popped.span)
-                            .over(popped.inner),
-                    );
-                }
-                Operator::Semicolon => {
-                    let popped = stack.pop();
-
-                    if let Some(Spanned {
-                        inner: Stack::Ident(id),
-                        span,
-                    }) = popped.as_ref().map(Spanned::as_ref)
-                        && !state.variables.contains_key(id)
-                    {
-                        return Err(
-                            span.over(RunErrorContainer::RunError(RunError::UnassignedVariable))
-                        );
-                    }
-
-                    let mut span = popped
-                        .map(|s| s.span)
-                        .unwrap_or_default()
-                        .from_self_to_other(token.span);
-
-                    while let Some(Spanned {
-                        inner: &Stack::Null,
-                        span: earlier_span,
-                    }) = stack.last().map(Spanned::as_ref)
-                    {
-                        span = earlier_span.from_self_to_other(span);
-
-                        let _ = stack.pop();
-                    }
-
-                    stack.push(span.over(Stack::Null));
+        if let Token::Operator(Operator::Define { name, params, body }) = token.inner {
+            define(
+                state,
+                &mut stack,
+                token.span.over(name),
+                params,
+                tokens.by_ref().take(body),
+            )?;
+        } else if token.inner == Token::Operator(Operator::Semicolon) {
+            let value = semicolon(state, &mut stack, token.span)?;
+
+            let silent = matches!(
+                last,
+                Some(Token::Operator(Operator::Equals | Operator::Print))
+            );
+
+            if let Some(value) = value {
+                result = Some(value);
+
+                if state.echo && !silent {
+                    state.output.print(value, state.radix);
                 }
-            },
+            }
+        } else {
+            step(state, &mut stack, token)?;
+        }
+
+        last = Some(token.inner);
+
+        if token.inner == Token::Operator(Operator::Semicolon)
+            && state.atomicity == Atomicity::Statement
+        {
+            state.commit();
         }
     }
 
-    Ok(())
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use parsr::{input::StrView, interner::Interner};
+
+    use std::cell::RefCell;
+
+    use crate::{output::Buffer, raw_token::parse_raw_tokens, tokens::resolved_tokens};
+
+    use super::*;
+
+    fn eval(state: &mut State, interner: &mut Interner, source: &str) -> Result<(), RunError> {
+        let mut view = StrView::new(source);
+
+        let tokens = resolved_tokens(parse_raw_tokens(&mut view, interner).unwrap());
+
+        run(state, tokens).map(|_| ()).map_err(|e| match e.inner {
+            RunErrorContainer::RunError(e) => e,
+            RunErrorContainer::TokenError(e) => panic!("{e}"),
+        })
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut interner = Interner::new();
+        let mut state = State::new();
+
+        eval(&mut state, &mut interner, "a = -2 ^ 2 + 10 / 4 * 2\n").unwrap();
+
+        let a = interner.insert("a");
+
+        assert_eq!(state.variables.get(&a), Some(&1.0));
+    }
+
+    #[test]
+    fn numeric_policy() {
+        let mut interner = Interner::new();
+        let mut state = State::new();
+
+        eval(&mut state, &mut interner, "a = 1 / 0\n").unwrap();
+
+        let a = interner.insert("a");
+
+        assert_eq!(state.variables.get(&a), Some(&f64::INFINITY));
+
+        state.numeric = NumericPolicy::Strict;
+
+        assert_eq!(
+            eval(&mut state, &mut interner, "a = 1 / (2 - 2)\n"),
+            Err(RunError::DivisionByZero)
+        );
+        assert_eq!(
+            eval(&mut state, &mut interner, "a = 10 ^ 400\n"),
+            Err(RunError::Overflow)
+        );
+        assert_eq!(
+            eval(&mut state, &mut interner, "a = (-1) ^ 0.5\n"),
+            Err(RunError::NotANumber)
+        );
+    }
+
+    #[test]
+    fn builtins() {
+        let mut interner = Interner::new();
+        let mut state = State::with_builtins(&mut interner);
+
+        eval(
+            &mut state,
+            &mut interner,
+            "a = max(1, sqrt(16), -2) + log(100) * abs(-1) ^ 2; b = log(8, 2)\n",
+        )
+        .unwrap();
+
+        let a = interner.insert("a");
+        let b = interner.insert("b");
+
+        assert_eq!(state.variables.get(&a), Some(&6.0));
+        assert_eq!(state.variables.get(&b), Some(&3.0));
+
+        assert_eq!(
+            eval(&mut state, &mut interner, "a = hypot(1)\n"),
+            Err(RunError::ArityMismatch {
+                expected: Arity::Exact(2),
+                found: 1
+            })
+        );
+        assert_eq!(
+            eval(&mut state, &mut interner, "a = nope(1)\n"),
+            Err(RunError::UnknownFunction)
+        );
+
+        for (expected, message) in [
+            (Arity::Exact(1), "Expected 1 argument but found 3"),
+            (
+                Arity::Between(1, 2),
+                "Expected 1 to 2 arguments but found 3",
+            ),
+            (
+                Arity::AtLeast(4),
+                "Expected at least 4 arguments but found 3",
+            ),
+        ] {
+            let error = RunError::ArityMismatch { expected, found: 3 };
+
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn user_functions() {
+        let mut interner = Interner::new();
+        let mut state = State::with_builtins(&mut interner);
+
+        eval(
+            &mut state,
+            &mut interner,
+            "f(x, y) = x * y + 1; a = f(2, 3); x = 10; b = f(x, 1) + x\n",
+        )
+        .unwrap();
+
+        eval(
+            &mut state,
+            &mut interner,
+            "g(a) = a + c; c = 1; d = g(5); k() = 42; e = k() + a\n",
+        )
+        .unwrap();
+
+        let get =
+            |interner: &mut Interner, name| state.variables.get(&interner.insert(name)).copied();
+
+        assert_eq!(get(&mut interner, "a"), Some(7.0));
+        assert_eq!(get(&mut interner, "b"), Some(21.0));
+        assert_eq!(get(&mut interner, "d"), Some(6.0));
+        assert_eq!(get(&mut interner, "e"), Some(49.0));
+        assert_eq!(get(&mut interner, "y"), None);
+
+        state.max_call_depth = 16;
+
+        assert_eq!(
+            eval(&mut state, &mut interner, "r(n) = r(n - 1); r(1)\n"),
+            Err(RunError::CallDepthExceeded)
+        );
+
+        // far deeper than the Rust stack could recurse
+        state.max_call_depth = 100_000;
+
+        assert_eq!(
+            eval(&mut state, &mut interner, "s(n) = 1 + s(n - 1) * 2; s(1)\n"),
+            Err(RunError::CallDepthExceeded)
+        );
+        assert!(state.frames.is_empty());
+        assert_eq!(
+            eval(&mut state, &mut interner, "f(1)\n"),
+            Err(RunError::ArityMismatch {
+                expected: Arity::Exact(2),
+                found: 1
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_tokens() {
+        let mut interner = Interner::new();
+        let mut state = State::new();
+
+        let f = interner.insert("f");
+        let x = interner.insert("x");
+
+        let define = Token::Operator(Operator::Define {
+            name: f,
+            params: 1,
+            body: 1,
+        });
+
+        let missing = RunErrorContainer::TokenError(TokenError::ProcessorError(
+            ProcessorError::ExpectedExpression,
+        ));
+
+        for (tokens, expected) in [
+            (
+                vec![
+                    Token::Value(Value::Number(1.0)),
+                    Token::Operator(Operator::Add),
+                ],
+                missing.clone(),
+            ),
+            (vec![Token::Operator(Operator::Print)], missing.clone()),
+            (vec![define, Token::Value(Value::Number(1.0))], missing),
+            (
+                vec![
+                    Token::Value(Value::Number(1.0)),
+                    define,
+                    Token::Value(Value::Number(1.0)),
+                ],
+                RunErrorContainer::RunError(RunError::InvalidParameter),
+            ),
+            (
+                vec![
+                    Token::Value(Value::Ident(x)),
+                    define,
+                    define,
+                    Token::Operator(Operator::Semicolon),
+                    Token::Value(Value::Number(1.0)),
+                    Token::Operator(Operator::Call(f, 1)),
+                ],
+                RunErrorContainer::RunError(RunError::NestedDefinition),
+            ),
+        ] {
+            let tokens = tokens
+                .into_iter()
+                .map(|token| Ok(Spanned::default_span(token)));
+
+            assert_eq!(run(&mut state, tokens).map_err(|e| e.inner), Err(expected));
+        }
+    }
+
+    #[test]
+    fn radix() {
+        assert_eq!(Radix::Hexadecimal.format(255.0), "0xFF");
+        assert_eq!(Radix::Binary.format(-10.0), "-0b1010");
+        assert_eq!(Radix::Octal.format(493.0), "0o755");
+        assert_eq!(Radix::Hexadecimal.format(2.5), "2.5");
+        assert_eq!(Radix::Decimal.format(255.0), "255");
+    }
+
+    #[test]
+    fn atomicity() {
+        let mut interner = Interner::new();
+        let mut state = State::with_builtins(&mut interner);
+
+        let a = interner.insert("a");
+        let f = interner.insert("f");
+
+        let source = "a = 5; f(x) = x; b = c + 1\n";
+
+        assert!(eval(&mut state, &mut interner, source).is_err());
+        assert_eq!(state.variables.get(&a), Some(&5.0));
+
+        state = State::with_builtins(&mut interner);
+        state.atomicity = Atomicity::Line;
+
+        assert!(eval(&mut state, &mut interner, source).is_err());
+        assert_eq!(state.variables.get(&a), None);
+        assert!(!state.functions.contains_key(&f));
+
+        // changes to things that already existed are undone to their old values
+        eval(&mut state, &mut interner, "a = 1; f(x) = x + 1\n").unwrap();
+
+        assert!(eval(&mut state, &mut interner, "a = 2; a = 3; f(x) = 0; c\n").is_err());
+        assert_eq!(state.variables.get(&a), Some(&1.0));
+
+        eval(&mut state, &mut interner, "a = f(a)\n").unwrap();
+        assert_eq!(state.variables.get(&a), Some(&2.0));
+
+        state.atomicity = Atomicity::Statement;
+
+        assert!(eval(&mut state, &mut interner, source).is_err());
+        assert_eq!(state.variables.get(&a), Some(&5.0));
+        assert!(state.functions.contains_key(&f));
+    }
+
+    #[test]
+    fn results() {
+        let mut interner = Interner::new();
+        let mut state = State::with_builtins(&mut interner);
+
+        let ans = interner.insert("ans");
+        state.ans = Some(ans);
+        state.record_history = true;
+
+        let get = |state: &State, name: &str, interner: &mut Interner| {
+            state.variables.get(&interner.insert(name)).copied()
+        };
+
+        eval(&mut state, &mut interner, "2 + 3; f(x) = x * 2; a = 4\n").unwrap();
+        assert_eq!(state.history, vec![5.0, 4.0]);
+        assert_eq!(state.variables.get(&ans), Some(&4.0));
+
+        eval(&mut state, &mut interner, "b = ans * _1 + f(_2)\n").unwrap();
+        assert_eq!(get(&state, "b", &mut interner), Some(28.0));
+        assert_eq!(state.variables.get(&ans), Some(&28.0));
+
+        assert_eq!(
+            eval(&mut state, &mut interner, "_4 + _0\n"),
+            Err(RunError::UnknownHistory)
+        );
+
+        // without history, results are still kept in `ans` but not in `history`
+        state.record_history = false;
+        state.history.clear();
+
+        eval(&mut state, &mut interner, "1 + 2; 3 + 4\n").unwrap();
+        assert_eq!(state.history, vec![]);
+        assert_eq!(state.variables.get(&ans), Some(&7.0));
+
+        assert_eq!(
+            eval(&mut state, &mut interner, "_1\n"),
+            Err(RunError::NoHistory)
+        );
+    }
+
+    #[test]
+    fn output() {
+        let mut interner = Interner::new();
+        let mut state = State::with_builtins(&mut interner);
+
+        let buffer = Buffer::default();
+        state.output = Box::new(buffer.clone());
+
+        eval(&mut state, &mut interner, "a = 2; % a * 3; 1 + 1\n").unwrap();
+        assert_eq!(buffer.take(), vec![2.0]);
+
+        state.echo = true;
+
+        eval(
+            &mut state,
+            &mut interner,
+            "a = 2; % a * 3; 1 + 1; f(x) = x; (b = 4) + 1\n",
+        )
+        .unwrap();
+        assert_eq!(buffer.take(), vec![2.0, 6.0, 2.0, 5.0]);
+
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let callback = printed.clone();
+
+        state.output = Box::new(move |value, radix: Radix| {
+            callback.borrow_mut().push(radix.format(value));
+        });
+        state.radix = Radix::Hexadecimal;
+
+        eval(&mut state, &mut interner, "% 255; 16\n").unwrap();
+        assert_eq!(
+            *printed.borrow(),
+            vec!["0xFF".to_owned(), "0x10".to_owned()]
+        );
+    }
+
+    #[test]
+    fn native_functions() {
+        let mut interner = Interner::new();
+        let mut state = State::with_builtins(&mut interner);
+
+        let rates = [0.1, 0.25];
+
+        state.register(&mut interner, "tax", Arity::Exact(1), |a| Ok(a[0] * 0.2));
+        state.register(&mut interner, "rate", Arity::Exact(1), move |a| {
+            rates
+                .get(a[0] as usize)
+                .copied()
+                .ok_or_else(|| format!("No rate with id {}", a[0]))
+        });
+        state.register(&mut interner, "total", Arity::AtLeast(0), |a| {
+            Ok(a.iter().sum())
+        });
+
+        eval(
+            &mut state,
+            &mut interner,
+            "a = tax(50) + rate(1) * total(1, 2, 3) + total()\n",
+        )
+        .unwrap();
+        assert_eq!(state.variables[&interner.insert("a")], 11.5);
+
+        let source = "b = 1 + rate(7)\n";
+        let mut view = StrView::new(source);
+        let tokens = resolved_tokens(parse_raw_tokens(&mut view, &mut interner).unwrap());
+        let err = run(&mut state, tokens).unwrap_err();
+
+        assert_eq!(
+            err.inner,
+            RunErrorContainer::RunError(RunError::Native("No rate with id 7".to_owned()))
+        );
+        assert_eq!((err.span.start, err.span.end), (8, 14));
+
+        assert_eq!(
+            eval(&mut state, &mut interner, "tax(1, 2)\n"),
+            Err(RunError::ArityMismatch {
+                expected: Arity::Exact(1),
+                found: 2
+            })
+        );
+    }
 }
@@ -0,0 +1,77 @@
+use calc_lang::{Diagnostic, Engine};
+
+/// Runs a script, printing a `name:line:column` diagnostic for each error.
+pub fn script(engine: &mut Engine, name: &str, source: &str) -> bool {
+    match run_script(engine, name, source) {
+        Ok(()) => true,
+        Err(diagnostics) => {
+            for diagnostic in diagnostics {
+                eprintln!("{diagnostic}");
+            }
+
+            false
+        }
+    }
+}
+
+/// Runs a script, returning its diagnostics formatted as `script` prints them. Nothing runs
+/// unless the whole script resolves, so a mistake near the end can't leave it half run.
+fn run_script(engine: &mut Engine, name: &str, source: &str) -> Result<(), Vec<String>> {
+    let diagnostic = |err: Diagnostic| format!("{name}:{}: error: {}", err.location, err.message);
+
+    let program = engine
+        .compile(source)
+        .map_err(|diagnostics| diagnostics.into_iter().map(diagnostic).collect::<Vec<_>>())?;
+
+    let result = engine.run(&program);
+
+    println!();
+
+    result.map(|_| ()).map_err(|err| vec![diagnostic(err)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locations() {
+        let mut engine = Engine::new();
+
+        let source = "a = 1 +* 2;\n# b\nb = (3;\n\nc = 5 6";
+
+        assert_eq!(
+            run_script(&mut engine, "broken.calc", source),
+            Err(vec![
+                "broken.calc:1:8: error: Expected expression".to_owned(),
+                "broken.calc:3:5: error: Unclosed right bracket".to_owned(),
+                "broken.calc:5:7: error: Did not expect expression".to_owned(),
+            ])
+        );
+
+        // nothing runs when a later line does not resolve
+        assert!(run_script(&mut engine, "late.calc", "d = 1;\ne = (2;").is_err());
+        assert_eq!(engine.get_var("d"), None);
+    }
+
+    #[test]
+    fn scripts() {
+        let mut engine = Engine::new();
+
+        let source = "# totals\na = 2;\nb = sqrt(a * 8);\nc = a + b + d";
+
+        assert_eq!(
+            run_script(&mut engine, "totals.calc", source),
+            Err(vec![
+                "totals.calc:4:13: error: Unassigned variable".to_owned()
+            ])
+        );
+        assert_eq!(engine.get_var("b"), Some(4.0));
+
+        assert_eq!(
+            run_script(&mut engine, "more.calc", "d = 1; c = a + b + d"),
+            Ok(())
+        );
+        assert_eq!(engine.get_var("c"), Some(7.0));
+    }
+}
@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{fmt::Display, iter};
 
 use parsr::{
     interner::Id,
@@ -12,11 +12,11 @@ use parsr::{
     },
 };
 
-use crate::raw_token::{RawToken, Symbol, UnexpectedCharacter};
+use crate::raw_token::{LexError, RawToken, Symbol};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TokenError {
-    RawToken(ParseIterError<UnexpectedCharacter>),
+    RawToken(ParseIterError<LexError>),
     ProcessorError(ProcessorError),
 }
 
@@ -35,6 +35,10 @@ pub enum ProcessorError {
     DidNotExpectExpression,
     UnclosedLeftBracket,
     UnclosedRightBracket,
+    UnexpectedComma,
+    RepeatedParameter,
+    AssigningToHistory,
+    SemicolonInBrackets,
 }
 
 impl Display for ProcessorError {
@@ -44,13 +48,21 @@ impl Display for ProcessorError {
             ProcessorError::DidNotExpectExpression => write!(f, "Did not expect expression"),
             ProcessorError::UnclosedLeftBracket => write!(f, "Unclosed left bracket"),
             ProcessorError::UnclosedRightBracket => write!(f, "Unclosed right bracket"),
+            ProcessorError::UnexpectedComma => write!(f, "Unexpected comma outside of a call"),
+            ProcessorError::RepeatedParameter => write!(f, "Repeated parameter name"),
+            ProcessorError::AssigningToHistory => {
+                write!(f, "Cannot assign to an earlier result")
+            }
+            ProcessorError::SemicolonInBrackets => {
+                write!(f, "Cannot end a statement inside brackets")
+            }
         }
     }
 }
 
-impl From<ParseIterError<UnexpectedCharacter>> for TokenError {
+impl From<ParseIterError<LexError>> for TokenError {
     #[inline(always)]
-    fn from(value: ParseIterError<UnexpectedCharacter>) -> Self {
+    fn from(value: ParseIterError<LexError>) -> Self {
         TokenError::RawToken(value)
     }
 }
@@ -71,6 +83,10 @@ pub enum Token {
 pub enum Value {
     Number(f64),
     Ident(Id),
+    /// A call without arguments
+    Call(Id),
+    /// An earlier result, `_1` being the first
+    History(usize),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -80,8 +96,22 @@ pub enum Operator {
     Sub,
     Mul,
     Div,
+    Pow,
+    Neg,
+    Pos,
     Print,
     Semicolon,
+    /// Separates call arguments, leaving them all on the stack
+    Comma,
+    /// A call taking its arguments from the stack, with the argument count
+    Call(Id, usize),
+    /// Defines a function taking its parameters from the stack, followed by the tokens of its
+    /// body. Emitted directly by `resolved_tokens`, it never goes through the processor.
+    Define {
+        name: Id,
+        params: usize,
+        body: usize,
+    },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -90,6 +120,15 @@ pub enum Ordering {
     RightParen,
 }
 
+/// What the processor resolves: the lexer's tokens, with calls marked by `mark_calls` once the
+/// whole statement is known.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Marked {
+    Raw(RawToken),
+    /// An identifier directly followed by `(`, along with its argument count
+    Call(Id, usize),
+}
+
 #[derive(Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 enum TokenTree {
     StartExpression,
@@ -139,22 +178,36 @@ impl IsResolvedToken for Operator {
     fn get_type(&self) -> TokenType {
         match self {
             Operator::Equals => TokenType::Precedence {
-                precedence: 1,
+                precedence: 2,
                 associativity: Associativity::Right,
             },
             Operator::Add | Operator::Sub => TokenType::Precedence {
-                precedence: 2,
+                precedence: 3,
                 associativity: Associativity::Left,
             },
             Operator::Mul | Operator::Div => TokenType::Precedence {
-                precedence: 3,
+                precedence: 4,
                 associativity: Associativity::Left,
             },
-            Operator::Print => TokenType::Precedence {
-                precedence: 4,
+            // Prefix operators never pop the operator they follow, so they must bind at least as
+            // tightly as any binary operator. Sharing a right associative level with `Pow` makes
+            // `-2 ^ 2` negate the power and `2 ^ -2` negate the exponent.
+            Operator::Pow | Operator::Neg | Operator::Pos | Operator::Print => {
+                TokenType::Precedence {
+                    precedence: 5,
+                    associativity: Associativity::Right,
+                }
+            }
+            // Binds tighter than `Pow` so that `f(x) ^ 2` squares the result
+            Operator::Call(..) => TokenType::Precedence {
+                precedence: 6,
                 associativity: Associativity::Right,
             },
-            Operator::Semicolon => TokenType::Precedence {
+            Operator::Comma => TokenType::Precedence {
+                precedence: 1,
+                associativity: Associativity::Left,
+            },
+            Operator::Semicolon | Operator::Define { .. } => TokenType::Precedence {
                 precedence: 0,
                 associativity: Associativity::Left,
             },
@@ -166,7 +219,7 @@ impl IsOrdering for Ordering {
     fn behaviour(&self) -> OrderingBehaviour {
         match self {
             Ordering::LeftParen => OrderingBehaviour::Right {
-                precedence: 5,
+                precedence: 7,
                 closed: true,
             },
             Ordering::RightParen => OrderingBehaviour::ClosedLeft,
@@ -192,36 +245,57 @@ impl FromStackEntry for TokenTree {
     }
 }
 
+#[allow(clippy::derivable_impls)]
 impl Default for TokenTree {
     fn default() -> Self {
         Self::StartExpression
     }
 }
 
-impl HasStateTransition<Spanned<RawToken>> for TokenTree {
+impl HasStateTransition<Spanned<Marked>> for TokenTree {
     type Token = Token;
     type Ordering = Ordering;
     type Error = ProcessorError;
 
     fn transition(
         self,
-        token: Spanned<RawToken>,
+        token: Spanned<Marked>,
     ) -> Result<StackEntry<Self::Token, Self::Ordering>, Spanned<Self::Error>> {
         match self {
             TokenTree::StartExpression => match token.inner {
-                RawToken::Ident(id) => Ok(StackEntry::Resolved(Spanned::new(
+                Marked::Raw(RawToken::Ident(id)) => Ok(StackEntry::Resolved(Spanned::new(
                     Token::Value(Value::Ident(id)),
                     token.span,
                 ))),
-                RawToken::Number(num) => Ok(StackEntry::Resolved(Spanned::new(
+                Marked::Raw(RawToken::Number(num)) => Ok(StackEntry::Resolved(Spanned::new(
                     Token::Value(Value::Number(num)),
                     token.span,
                 ))),
-                RawToken::Symbol(symbol) => match symbol {
+                Marked::Call(id, 0) => Ok(StackEntry::Resolved(Spanned::new(
+                    Token::Value(Value::Call(id)),
+                    token.span,
+                ))),
+                Marked::Call(id, arguments) => Ok(StackEntry::Resolved(Spanned::new(
+                    Token::Operator(Operator::Call(id, arguments)),
+                    token.span,
+                ))),
+                Marked::Raw(RawToken::History(index)) => Ok(StackEntry::Resolved(Spanned::new(
+                    Token::Value(Value::History(index)),
+                    token.span,
+                ))),
+                Marked::Raw(RawToken::Symbol(symbol)) => match symbol {
                     Symbol::LeftParen => Ok(StackEntry::Ordering(Spanned::new(
                         Ordering::LeftParen,
                         token.span,
                     ))),
+                    Symbol::Add => Ok(StackEntry::Resolved(Spanned::new(
+                        Token::Operator(Operator::Pos),
+                        token.span,
+                    ))),
+                    Symbol::Sub => Ok(StackEntry::Resolved(Spanned::new(
+                        Token::Operator(Operator::Neg),
+                        token.span,
+                    ))),
                     Symbol::Print => Ok(StackEntry::Resolved(Spanned::new(
                         Token::Operator(Operator::Print),
                         token.span,
@@ -234,7 +308,7 @@ impl HasStateTransition<Spanned<RawToken>> for TokenTree {
                 },
             },
             TokenTree::EndExpression => match token.inner {
-                RawToken::Symbol(symbol) => match symbol {
+                Marked::Raw(RawToken::Symbol(symbol)) => match symbol {
                     Symbol::Equals => Ok(StackEntry::Resolved(Spanned::new(
                         Token::Operator(Operator::Equals),
                         token.span,
@@ -255,6 +329,10 @@ impl HasStateTransition<Spanned<RawToken>> for TokenTree {
                         Token::Operator(Operator::Div),
                         token.span,
                     ))),
+                    Symbol::Pow => Ok(StackEntry::Resolved(Spanned::new(
+                        Token::Operator(Operator::Pow),
+                        token.span,
+                    ))),
                     Symbol::RightParen => Ok(StackEntry::Ordering(Spanned::new(
                         Ordering::RightParen,
                         token.span,
@@ -263,6 +341,10 @@ impl HasStateTransition<Spanned<RawToken>> for TokenTree {
                         Token::Operator(Operator::Semicolon),
                         token.span,
                     ))),
+                    Symbol::Comma => Ok(StackEntry::Resolved(Spanned::new(
+                        Token::Operator(Operator::Comma),
+                        token.span,
+                    ))),
                     _ => Err(token.span.over(ProcessorError::DidNotExpectExpression)),
                 },
                 _ => Err(token.span.over(ProcessorError::DidNotExpectExpression)),
@@ -271,14 +353,368 @@ impl HasStateTransition<Spanned<RawToken>> for TokenTree {
     }
 }
 
+type RawTokenResult = Result<Spanned<RawToken>, Spanned<TokenError>>;
+
+type MarkedResult = Result<Spanned<Marked>, Spanned<TokenError>>;
+
+type TokenResult = Result<Spanned<Token>, Spanned<TokenError>>;
+
+/// Takes the next statement up to a top level semicolon, returning it along with the semicolon
+/// that ended it, if any.
+fn statement(
+    mut next: impl FnMut() -> Option<RawTokenResult>,
+) -> Option<(Vec<RawTokenResult>, Option<Spanned<RawToken>>)> {
+    let mut statement = Vec::new();
+    let mut depth = 0usize;
+
+    while let Some(token) = next() {
+        match token {
+            Ok(Spanned {
+                inner: RawToken::Symbol(Symbol::Semicolon),
+                ..
+            }) if depth == 0 => return Some((statement, token.ok())),
+            Ok(Spanned {
+                inner: RawToken::Symbol(Symbol::LeftParen),
+                ..
+            }) => depth += 1,
+            Ok(Spanned {
+                inner: RawToken::Symbol(Symbol::RightParen),
+                ..
+            }) => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+
+        statement.push(token);
+    }
+
+    (!statement.is_empty()).then_some((statement, None))
+}
+
+/// Counts the arguments of a call, starting from its left bracket.
+fn count_arguments(tokens: &[RawTokenResult]) -> usize {
+    let mut depth = 0usize;
+    let mut arguments = 1;
+
+    for token in tokens {
+        match token {
+            Ok(Spanned {
+                inner: RawToken::Symbol(Symbol::LeftParen),
+                ..
+            }) => depth += 1,
+            Ok(Spanned {
+                inner: RawToken::Symbol(Symbol::RightParen),
+                ..
+            }) => {
+                depth -= 1;
+
+                if depth == 0 {
+                    break;
+                }
+            }
+            Ok(Spanned {
+                inner: RawToken::Symbol(Symbol::Comma),
+                ..
+            }) if depth == 1 => arguments += 1,
+            _ => (),
+        }
+    }
+
+    arguments
+}
+
+/// Turns every identifier directly followed by `(` into a [`Marked::Call`]. The brackets of a
+/// call without arguments are dropped so that it resolves to a single value.
+fn mark_calls(tokens: Vec<RawTokenResult>) -> Vec<MarkedResult> {
+    let raw = |token: RawTokenResult| token.map(|token| token.map(Marked::Raw));
+
+    let mut marked = Vec::with_capacity(tokens.len());
+    // Whether each open bracket belongs to a call
+    let mut brackets = Vec::new();
+    let mut index = 0;
+
+    while let Some(&token) = tokens.get(index) {
+        index += 1;
+
+        let Ok(spanned) = token else {
+            marked.push(raw(token));
+            continue;
+        };
+
+        match spanned.inner {
+            RawToken::Ident(id)
+                if matches!(
+                    tokens.get(index),
+                    Some(Ok(Spanned {
+                        inner: RawToken::Symbol(Symbol::LeftParen),
+                        ..
+                    }))
+                ) =>
+            {
+                if let Some(Ok(close)) = tokens.get(index + 1)
+                    && close.inner == RawToken::Symbol(Symbol::RightParen)
+                {
+                    marked.push(Ok(spanned
+                        .span
+                        .from_self_to_other(close.span)
+                        .over(Marked::Call(id, 0))));
+
+                    index += 2;
+                } else {
+                    let arguments = count_arguments(&tokens[index..]);
+
+                    marked.push(Ok(spanned.span.over(Marked::Call(id, arguments))));
+                    marked.push(raw(tokens[index]));
+                    brackets.push(true);
+
+                    index += 1;
+                }
+            }
+            RawToken::History(_)
+                if matches!(
+                    tokens.get(index),
+                    Some(Ok(Spanned {
+                        inner: RawToken::Symbol(Symbol::Equals),
+                        ..
+                    }))
+                ) =>
+            {
+                marked.push(Err(spanned.span.over(TokenError::ProcessorError(
+                    ProcessorError::AssigningToHistory,
+                ))));
+            }
+            RawToken::Symbol(Symbol::LeftParen) => {
+                marked.push(raw(token));
+                brackets.push(false);
+            }
+            RawToken::Symbol(Symbol::RightParen) => {
+                marked.push(raw(token));
+                brackets.pop();
+            }
+            RawToken::Symbol(Symbol::Comma) if brackets.last() != Some(&true) => {
+                marked.push(Err(spanned
+                    .span
+                    .over(TokenError::ProcessorError(ProcessorError::UnexpectedComma))));
+            }
+            _ => marked.push(raw(token)),
+        }
+    }
+
+    marked
+}
+
+/// The head of a statement of the form `name(params) = body`.
+struct Definition {
+    /// Spans from the name to the `=`
+    name: Spanned<Id>,
+    params: Vec<Spanned<Id>>,
+    /// Where the body starts in the statement
+    body: usize,
+}
+
+impl Definition {
+    fn parse(statement: &[RawTokenResult]) -> Option<Self> {
+        let raw = |index: usize| match statement.get(index) {
+            Some(Ok(token)) => Some(*token),
+            _ => None,
+        };
+
+        let RawToken::Ident(name) = raw(0)?.inner else {
+            return None;
+        };
+
+        if raw(1)?.inner != RawToken::Symbol(Symbol::LeftParen) {
+            return None;
+        }
+
+        let mut params = Vec::new();
+        let mut index = 2;
+
+        if raw(index)?.inner == RawToken::Symbol(Symbol::RightParen) {
+            index += 1;
+        } else {
+            loop {
+                let param = raw(index)?;
+
+                let RawToken::Ident(id) = param.inner else {
+                    return None;
+                };
+
+                params.push(param.span.over(id));
+
+                index += 1;
+
+                match raw(index)?.inner {
+                    RawToken::Symbol(Symbol::Comma) => index += 1,
+                    RawToken::Symbol(Symbol::RightParen) => {
+                        index += 1;
+                        break;
+                    }
+                    _ => return None,
+                }
+            }
+        }
+
+        let equals = raw(index)?;
+
+        // A definition without a body is left for the processor to report
+        if equals.inner != RawToken::Symbol(Symbol::Equals) || index + 1 >= statement.len() {
+            return None;
+        }
+
+        Some(Self {
+            name: raw(0)?.span.from_self_to_other(equals.span).over(name),
+            params,
+            body: index + 1,
+        })
+    }
+
+    /// The first parameter named the same as one before it.
+    fn repeated_param(&self) -> Option<Spanned<Id>> {
+        self.params.iter().enumerate().find_map(|(index, param)| {
+            self.params[..index]
+                .iter()
+                .any(|earlier| earlier.inner == param.inner)
+                .then_some(*param)
+        })
+    }
+
+    fn resolve(self, body: usize) -> Vec<TokenResult> {
+        let params = self.params.len();
+
+        let mut head = self
+            .params
+            .into_iter()
+            .map(|param| Ok(param.map(|id| Token::Value(Value::Ident(id)))))
+            .collect::<Vec<_>>();
+
+        head.push(Ok(self.name.map(|name| {
+            Token::Operator(Operator::Define { name, params, body })
+        })));
+
+        head
+    }
+}
+
+/// Resolves the lexer's tokens one statement at a time, so an error in one statement does not
+/// stop the statements after it from being resolved and their errors reported.
 pub fn resolved_tokens(
-    tokens: impl Iterator<
-        Item = Result<Spanned<RawToken>, ParseIterError<Spanned<UnexpectedCharacter>>>,
-    >,
-) -> impl Iterator<Item = Result<Spanned<Token>, Spanned<TokenError>>> {
-    CreateTokenProcessor::<Spanned<RawToken>, TokenTree, State, TokenError>::new(
-        tokens.map(|r| r.map_err(|e| e.spanned().map(TokenError::RawToken))),
+    tokens: impl Iterator<Item = Result<Spanned<RawToken>, ParseIterError<Spanned<LexError>>>>,
+) -> impl Iterator<Item = TokenResult> {
+    let mut tokens = tokens
+        .map(|r| r.map_err(|e| e.spanned().map(TokenError::RawToken)))
+        .fuse();
+
+    // tokens handed back by `resolve_statement`, last first
+    let mut pending = Vec::new();
+
+    iter::from_fn(move || {
+        let (statement, semicolon) = statement(|| pending.pop().or_else(|| tokens.next()))?;
+
+        let (resolved, rest) = resolve_statement(statement, semicolon);
+
+        pending.extend(rest.into_iter().rev());
+
+        Some(resolved)
+    })
+    .flatten()
+}
+
+/// Resolves the tokens of a statement's body, ending them at their first error.
+fn process(tokens: &[RawTokenResult]) -> Vec<TokenResult> {
+    let mut body = CreateTokenProcessor::<Spanned<Marked>, TokenTree, State, TokenError>::new(
+        mark_calls(tokens.to_vec()).into_iter(),
     )
+    .collect::<Vec<_>>();
+
+    // Errors after the first in a statement usually follow from it
+    if let Some(error) = body.iter().position(Result::is_err) {
+        body.truncate(error + 1);
+    }
+
+    body
+}
+
+/// Resolves a statement, ending it at its first error. Brackets may hide where a statement was
+/// meant to end, so after an error the statement is cut at its first `;`, which can only be inside
+/// brackets, and the tokens after that are returned to be split into statements again.
+fn resolve_statement(
+    statement: Vec<RawTokenResult>,
+    mut semicolon: Option<Spanned<RawToken>>,
+) -> (Vec<TokenResult>, Vec<RawTokenResult>) {
+    let mut definition = Definition::parse(&statement);
+
+    let start = definition.as_ref().map_or(0, |d| d.body);
+
+    let mut body = process(&statement[start..]);
+
+    let mut rest = Vec::new();
+
+    if let Some(Err(err)) = body.last() {
+        let resync = statement.iter().position(|token| {
+            matches!(
+                token,
+                Ok(Spanned {
+                    inner: RawToken::Symbol(Symbol::Semicolon),
+                    ..
+                })
+            )
+        });
+
+        if let Some(resync) = resync {
+            let err = *err;
+
+            rest = statement[resync + 1..].to_vec();
+            rest.extend(semicolon.map(Ok));
+
+            semicolon = statement[resync].ok();
+
+            // the tokens after the cut are resolved again from `rest`
+            body = process(&statement[start..resync]);
+
+            if !body.iter().any(Result::is_err) {
+                body.push(Err(err));
+            }
+        }
+    }
+
+    if let Some(param) = definition.as_ref().and_then(Definition::repeated_param) {
+        definition = None;
+        body = vec![Err(param.span.over(TokenError::ProcessorError(
+            ProcessorError::RepeatedParameter,
+        )))];
+    }
+
+    // Statements are split at every `;` outside of brackets, so any left ended one inside them
+    let inner = body.iter().position(|token| {
+        matches!(
+            token,
+            Ok(Spanned {
+                inner: Token::Operator(Operator::Semicolon),
+                ..
+            })
+        )
+    });
+
+    if let (Some(inner), None) = (inner, body.iter().position(Result::is_err)) {
+        let span = body[inner].as_ref().unwrap().span;
+
+        body.truncate(inner);
+        body.push(Err(span.over(TokenError::ProcessorError(
+            ProcessorError::SemicolonInBrackets,
+        ))));
+    }
+
+    let resolved = definition
+        .map(|definition| definition.resolve(body.len()))
+        .unwrap_or_default()
+        .into_iter()
+        .chain(body)
+        .chain(
+            semicolon.map(|semicolon| Ok(semicolon.map(|_| Token::Operator(Operator::Semicolon)))),
+        )
+        .collect();
+
+    (resolved, rest)
 }
 
 #[cfg(test)]
@@ -315,4 +751,323 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn unary() {
+        let tokens = [
+            RawToken::Number(2.0),
+            RawToken::Symbol(Symbol::Mul),
+            RawToken::Symbol(Symbol::Sub),
+            RawToken::Symbol(Symbol::LeftParen),
+            RawToken::Symbol(Symbol::Add),
+            RawToken::Number(1.0),
+            RawToken::Symbol(Symbol::Sub),
+            RawToken::Number(3.0),
+            RawToken::Symbol(Symbol::RightParen),
+        ];
+
+        let tokens = resolved_tokens(
+            tokens
+                .into_iter()
+                .map(Spanned::default_span)
+                .map(Result::Ok),
+        );
+
+        assert_eq!(
+            tokens.collect::<Vec<_>>(),
+            vec![
+                Ok(Spanned::default_span(Token::Value(Value::Number(2.0)))),
+                Ok(Spanned::default_span(Token::Value(Value::Number(1.0)))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Pos))),
+                Ok(Spanned::default_span(Token::Value(Value::Number(3.0)))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Sub))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Neg))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Mul))),
+            ]
+        );
+    }
+
+    #[test]
+    fn power() {
+        // -2 ^ 3 ^ 2 * 2 ^ -1
+        let tokens = [
+            RawToken::Symbol(Symbol::Sub),
+            RawToken::Number(2.0),
+            RawToken::Symbol(Symbol::Pow),
+            RawToken::Number(3.0),
+            RawToken::Symbol(Symbol::Pow),
+            RawToken::Number(2.0),
+            RawToken::Symbol(Symbol::Mul),
+            RawToken::Number(2.0),
+            RawToken::Symbol(Symbol::Pow),
+            RawToken::Symbol(Symbol::Sub),
+            RawToken::Number(1.0),
+        ];
+
+        let tokens = resolved_tokens(
+            tokens
+                .into_iter()
+                .map(Spanned::default_span)
+                .map(Result::Ok),
+        );
+
+        assert_eq!(
+            tokens.collect::<Vec<_>>(),
+            vec![
+                Ok(Spanned::default_span(Token::Value(Value::Number(2.0)))),
+                Ok(Spanned::default_span(Token::Value(Value::Number(3.0)))),
+                Ok(Spanned::default_span(Token::Value(Value::Number(2.0)))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Pow))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Pow))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Neg))),
+                Ok(Spanned::default_span(Token::Value(Value::Number(2.0)))),
+                Ok(Spanned::default_span(Token::Value(Value::Number(1.0)))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Neg))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Pow))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Mul))),
+            ]
+        );
+    }
+
+    #[test]
+    fn calls() {
+        let max = unsafe { Id::from_usize(0) };
+        let f = unsafe { Id::from_usize(1) };
+
+        // max(1, 2 + 3) * f()
+        let tokens = [
+            RawToken::Ident(max),
+            RawToken::Symbol(Symbol::LeftParen),
+            RawToken::Number(1.0),
+            RawToken::Symbol(Symbol::Comma),
+            RawToken::Number(2.0),
+            RawToken::Symbol(Symbol::Add),
+            RawToken::Number(3.0),
+            RawToken::Symbol(Symbol::RightParen),
+            RawToken::Symbol(Symbol::Mul),
+            RawToken::Ident(f),
+            RawToken::Symbol(Symbol::LeftParen),
+            RawToken::Symbol(Symbol::RightParen),
+        ];
+
+        let tokens = resolved_tokens(
+            tokens
+                .into_iter()
+                .map(Spanned::default_span)
+                .map(Result::Ok),
+        );
+
+        assert_eq!(
+            tokens.collect::<Vec<_>>(),
+            vec![
+                Ok(Spanned::default_span(Token::Value(Value::Number(1.0)))),
+                Ok(Spanned::default_span(Token::Value(Value::Number(2.0)))),
+                Ok(Spanned::default_span(Token::Value(Value::Number(3.0)))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Add))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Comma))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Call(
+                    max, 2
+                )))),
+                Ok(Spanned::default_span(Token::Value(Value::Call(f)))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Mul))),
+            ]
+        );
+    }
+
+    #[test]
+    fn stray_comma() {
+        let tokens = [
+            RawToken::Symbol(Symbol::LeftParen),
+            RawToken::Number(1.0),
+            RawToken::Symbol(Symbol::Comma),
+            RawToken::Number(2.0),
+            RawToken::Symbol(Symbol::RightParen),
+        ];
+
+        let mut tokens = resolved_tokens(
+            tokens
+                .into_iter()
+                .map(Spanned::default_span)
+                .map(Result::Ok),
+        );
+
+        assert!(tokens.any(|token| token
+            == Err(Spanned::default_span(TokenError::ProcessorError(
+                ProcessorError::UnexpectedComma
+            )))));
+    }
+
+    #[test]
+    fn definition() {
+        let f = unsafe { Id::from_usize(0) };
+        let x = unsafe { Id::from_usize(1) };
+        let y = unsafe { Id::from_usize(2) };
+
+        // f(x, y) = x * f(y)
+        let tokens = [
+            RawToken::Ident(f),
+            RawToken::Symbol(Symbol::LeftParen),
+            RawToken::Ident(x),
+            RawToken::Symbol(Symbol::Comma),
+            RawToken::Ident(y),
+            RawToken::Symbol(Symbol::RightParen),
+            RawToken::Symbol(Symbol::Equals),
+            RawToken::Ident(x),
+            RawToken::Symbol(Symbol::Mul),
+            RawToken::Ident(f),
+            RawToken::Symbol(Symbol::LeftParen),
+            RawToken::Ident(y),
+            RawToken::Symbol(Symbol::RightParen),
+        ];
+
+        let tokens = resolved_tokens(
+            tokens
+                .into_iter()
+                .map(Spanned::default_span)
+                .map(Result::Ok),
+        );
+
+        assert_eq!(
+            tokens.collect::<Vec<_>>(),
+            vec![
+                Ok(Spanned::default_span(Token::Value(Value::Ident(x)))),
+                Ok(Spanned::default_span(Token::Value(Value::Ident(y)))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Define {
+                    name: f,
+                    params: 2,
+                    body: 4
+                }))),
+                Ok(Spanned::default_span(Token::Value(Value::Ident(x)))),
+                Ok(Spanned::default_span(Token::Value(Value::Ident(y)))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Call(f, 1)))),
+                Ok(Spanned::default_span(Token::Operator(Operator::Mul))),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_parameter() {
+        let f = unsafe { Id::from_usize(0) };
+        let x = unsafe { Id::from_usize(1) };
+
+        // f(x, x) = x
+        let tokens = [
+            RawToken::Ident(f),
+            RawToken::Symbol(Symbol::LeftParen),
+            RawToken::Ident(x),
+            RawToken::Symbol(Symbol::Comma),
+            RawToken::Ident(x),
+            RawToken::Symbol(Symbol::RightParen),
+            RawToken::Symbol(Symbol::Equals),
+            RawToken::Ident(x),
+        ];
+
+        let tokens = resolved_tokens(
+            tokens
+                .into_iter()
+                .map(Spanned::default_span)
+                .map(Result::Ok),
+        );
+
+        assert_eq!(
+            tokens.collect::<Vec<_>>(),
+            vec![Err(Spanned::default_span(TokenError::ProcessorError(
+                ProcessorError::RepeatedParameter
+            )))]
+        );
+    }
+
+    #[test]
+    fn assigning_to_history() {
+        // _1 = 2
+        let tokens = [
+            RawToken::History(1),
+            RawToken::Symbol(Symbol::Equals),
+            RawToken::Number(2.0),
+        ];
+
+        let mut tokens = resolved_tokens(
+            tokens
+                .into_iter()
+                .map(Spanned::default_span)
+                .map(Result::Ok),
+        );
+
+        assert_eq!(
+            tokens.next(),
+            Some(Err(Spanned::default_span(TokenError::ProcessorError(
+                ProcessorError::AssigningToHistory
+            ))))
+        );
+    }
+
+    #[test]
+    fn recovery() {
+        // 1 * * * 2; (3; 4
+        let tokens = [
+            RawToken::Number(1.0),
+            RawToken::Symbol(Symbol::Mul),
+            RawToken::Symbol(Symbol::Mul),
+            RawToken::Symbol(Symbol::Mul),
+            RawToken::Number(2.0),
+            RawToken::Symbol(Symbol::Semicolon),
+            RawToken::Symbol(Symbol::LeftParen),
+            RawToken::Number(3.0),
+            RawToken::Symbol(Symbol::Semicolon),
+            RawToken::Number(4.0),
+        ];
+
+        let tokens = resolved_tokens(
+            tokens
+                .into_iter()
+                .map(Spanned::default_span)
+                .map(Result::Ok),
+        )
+        .collect::<Vec<_>>();
+
+        let errors = tokens
+            .iter()
+            .filter_map(|token| token.err().map(|err| err.inner))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            errors,
+            [
+                TokenError::ProcessorError(ProcessorError::ExpectedExpression),
+                TokenError::ProcessorError(ProcessorError::UnclosedRightBracket),
+            ]
+        );
+
+        assert_eq!(
+            tokens.last(),
+            Some(&Ok(Spanned::default_span(Token::Value(Value::Number(4.0)))))
+        );
+
+        // (1; 2) + 3
+        let tokens = [
+            RawToken::Symbol(Symbol::LeftParen),
+            RawToken::Number(1.0),
+            RawToken::Symbol(Symbol::Semicolon),
+            RawToken::Number(2.0),
+            RawToken::Symbol(Symbol::RightParen),
+            RawToken::Symbol(Symbol::Add),
+            RawToken::Number(3.0),
+        ];
+
+        let errors = resolved_tokens(
+            tokens
+                .into_iter()
+                .map(Spanned::default_span)
+                .map(Result::Ok),
+        )
+        .filter_map(|token| token.err().map(|err| err.inner))
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            errors,
+            [TokenError::ProcessorError(
+                ProcessorError::SemicolonInBrackets
+            )]
+        );
+    }
 }